@@ -1,22 +1,25 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::amount::Amount;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Account {
+    // named like the column of the CSV output; older ledgers stored it as `id`
+    #[serde(rename = "client", alias = "id")]
     pub id: u16,
-    pub available: f32,
-    pub held: f32,
-    pub total: f32,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
     pub locked: bool,
 }
 
 impl Account {
-    pub const DB_NAME: &'static str = "account_db";
     pub fn new(id: u16) -> Account {
         Account {
             id,
-            available: 0f32,
-            held: 0f32,
-            total: 0f32,
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            total: Amount::ZERO,
             locked: false,
         }
     }
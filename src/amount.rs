@@ -0,0 +1,276 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Amounts are stored as an integer number of ten-thousandths, so every value with up to four
+// decimal places is represented exactly and arithmetic never drifts like f32 does.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i64);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmountError {
+    Invalid(String),
+    TooManyDecimals(String),
+    Overflow,
+}
+
+impl Amount {
+    pub const DECIMALS: u32 = 4;
+    pub const SCALE: i64 = 10i64.pow(Amount::DECIMALS);
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_scaled(units: i64) -> Amount {
+        Amount(units)
+    }
+
+    pub fn checked_add(self, rhs: Amount) -> Result<Amount, AmountError> {
+        self.0
+            .checked_add(rhs.0)
+            .map(Amount)
+            .ok_or(AmountError::Overflow)
+    }
+
+    pub fn checked_sub(self, rhs: Amount) -> Result<Amount, AmountError> {
+        self.0
+            .checked_sub(rhs.0)
+            .map(Amount)
+            .ok_or(AmountError::Overflow)
+    }
+
+    pub fn checked_abs(self) -> Result<Amount, AmountError> {
+        self.0
+            .checked_abs()
+            .map(Amount)
+            .ok_or(AmountError::Overflow)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = AmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AmountError::Invalid(s.to_string());
+        let trimmed = s.trim();
+        let (negative, digits) = match trimmed.as_bytes().first() {
+            Some(b'-') => (true, &trimmed[1..]),
+            Some(b'+') => (false, &trimmed[1..]),
+            _ => (false, trimmed),
+        };
+        let (int_part, frac_part) = match digits.split_once('.') {
+            Some((int_part, frac_part)) => (int_part, frac_part),
+            None => (digits, ""),
+        };
+
+        if int_part.is_empty() && frac_part.is_empty() {
+            return Err(invalid());
+        }
+        if !int_part
+            .bytes()
+            .chain(frac_part.bytes())
+            .all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        if frac_part.len() > Amount::DECIMALS as usize {
+            return Err(AmountError::TooManyDecimals(s.to_string()));
+        }
+
+        let mut units: i64 = 0;
+        let padding = Amount::DECIMALS as usize - frac_part.len();
+        let all_digits = int_part
+            .bytes()
+            .chain(frac_part.bytes())
+            .chain(std::iter::repeat_n(b'0', padding));
+        for b in all_digits {
+            // accumulate on the negative side so i64::MIN stays representable
+            let digit = i64::from(b - b'0');
+            units = units
+                .checked_mul(10)
+                .and_then(|u| u.checked_sub(digit))
+                .ok_or(AmountError::Overflow)?;
+        }
+
+        if negative {
+            Ok(Amount(units))
+        } else {
+            units.checked_neg().map(Amount).ok_or(AmountError::Overflow)
+        }
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let scale = Amount::SCALE as u64;
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            abs / scale,
+            abs % scale,
+            width = Amount::DECIMALS as usize
+        )
+    }
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmountError::Invalid(s) => write!(f, "Invalid amount: {:?}", s),
+            AmountError::TooManyDecimals(s) => write!(
+                f,
+                "Amount {:?} has more than {} decimal places",
+                s,
+                Amount::DECIMALS
+            ),
+            AmountError::Overflow => write!(f, "Amount overflow"),
+        }
+    }
+}
+
+impl Error for AmountError {}
+
+// serialized as a decimal string, so storage and output never go through a float
+impl Serialize for Amount {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+// read from the text of the field, never through a float. CSV fields are handed over as they
+// are written, and JSON numbers are turned back into their text before getting here (see
+// `input::json_row`), so `1e3` or a 17th decimal are rejected instead of rounded
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        de.deserialize_str(AmountVisitor)
+    }
+}
+
+struct AmountVisitor;
+
+impl Visitor<'_> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "a decimal amount with at most {} decimal places",
+            Amount::DECIMALS
+        )
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Amount, E> {
+        v.parse().map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!("733.38".parse::<Amount>().unwrap().to_string(), "733.3800");
+        assert_eq!("1".parse::<Amount>().unwrap().to_string(), "1.0000");
+        assert_eq!(".5".parse::<Amount>().unwrap().to_string(), "0.5000");
+        assert_eq!("-0.0001".parse::<Amount>().unwrap().to_string(), "-0.0001");
+        assert_eq!(
+            "+2.1234".parse::<Amount>().unwrap(),
+            Amount::from_scaled(21234)
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid() {
+        assert_eq!(
+            "1.23456".parse::<Amount>(),
+            Err(AmountError::TooManyDecimals("1.23456".to_string()))
+        );
+        assert!(matches!("".parse::<Amount>(), Err(AmountError::Invalid(_))));
+        assert!(matches!(
+            ".".parse::<Amount>(),
+            Err(AmountError::Invalid(_))
+        ));
+        assert!(matches!(
+            "1e5".parse::<Amount>(),
+            Err(AmountError::Invalid(_))
+        ));
+        assert!(matches!(
+            "1.2.3".parse::<Amount>(),
+            Err(AmountError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_overflow() {
+        assert_eq!(
+            "-922337203685477.5808".parse::<Amount>(),
+            Ok(Amount::from_scaled(i64::MIN))
+        );
+        assert_eq!(
+            "922337203685477.5808".parse::<Amount>(),
+            Err(AmountError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_exact_arithmetic() {
+        let cent: Amount = "0.1".parse().unwrap();
+        let mut total = Amount::ZERO;
+        for _ in 0..10_000 {
+            total = total.checked_add(cent).unwrap();
+        }
+        assert_eq!(total, "1000".parse().unwrap());
+    }
+
+    #[test]
+    fn test_checked_overflow() {
+        let max = Amount::from_scaled(i64::MAX);
+        let min = Amount::from_scaled(i64::MIN);
+        assert_eq!(
+            max.checked_add(Amount::from_scaled(1)),
+            Err(AmountError::Overflow)
+        );
+        assert_eq!(
+            min.checked_sub(Amount::from_scaled(1)),
+            Err(AmountError::Overflow)
+        );
+        assert_eq!(min.checked_abs(), Err(AmountError::Overflow));
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let amount: Amount = "12.5".parse().unwrap();
+        let json = serde_json::to_string(&amount).unwrap();
+        assert_eq!(json, "\"12.5000\"");
+        assert_eq!(serde_json::from_str::<Amount>(&json).unwrap(), amount);
+        assert!(serde_json::from_str::<Amount>("\"0.00001\"").is_err());
+    }
+
+    #[test]
+    fn test_csv_fields_are_read_as_text() {
+        let read = |field: &str| {
+            let record = csv::StringRecord::from(vec![field]);
+            record
+                .deserialize::<(Amount,)>(None)
+                .map(|(amount,)| amount)
+        };
+        assert_eq!(read("2.5").unwrap(), "2.5".parse().unwrap());
+        assert!(read("1.00000000000000001").is_err());
+        assert!(read("1e3").is_err());
+        assert_eq!(
+            read("123456789012.5").unwrap(),
+            Amount::from_scaled(1_234_567_890_125_000)
+        );
+    }
+}
@@ -0,0 +1,979 @@
+use std::io::{Read, Write};
+
+use crate::account::Account;
+use crate::amount::Amount;
+use crate::error::{Result, RuleViolation};
+use crate::input::{InputFormat, Rows};
+use crate::invariants::{InvariantCheck, Invariants, Violation};
+use crate::journal::{JournalEntry, JournalFilter, Outcome};
+use crate::output::{self, OutputFormat};
+use crate::report::Rejection;
+use crate::storage::{AccountIter, Commit, Storage};
+use crate::transaction::{DisputeEvent, Transaction, TxState, TxType};
+
+// Applies transactions to accounts, keeping both in a pluggable storage backend
+pub struct Engine<S: Storage> {
+    storage: S,
+    config: Config,
+    // rows applied, or journaled as rejected, since the storage was last flushed
+    unflushed: u64,
+    invariants: Invariants,
+    // where the rows given to `apply_row` come from, recorded in the journal
+    source: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Config {
+    pub lock_policy: LockPolicy,
+    pub withdrawal_disputes: WithdrawalDisputePolicy,
+    pub durability: Durability,
+    pub invariants: InvariantCheck,
+}
+
+// When applied rows are flushed to storage, trading crash safety for throughput
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    // after every row that was applied or journaled as rejected, nothing is lost on a crash
+    #[default]
+    EveryRow,
+    // after every N such rows, a crash loses at most the last N - 1 of them
+    EveryN(u64),
+    // only when `Engine::flush` is called, a crash can lose the whole run
+    AtEnd,
+}
+
+// How a dispute that references a withdrawal is handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WithdrawalDisputePolicy {
+    // the dispute is rejected, only deposits can be disputed
+    #[default]
+    Reject,
+    // the withdrawn amount is credited back to the account but held until the dispute settles:
+    // a resolve takes the credit back, a chargeback releases it to the client
+    ProvisionalCredit,
+}
+
+// What is still accepted on an account locked by a chargeback
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockPolicy {
+    // every transaction is rejected
+    #[default]
+    BlockAll,
+    // deposits and withdrawals are rejected, but disputes, resolves and chargebacks
+    // of earlier transactions can still go through
+    AllowDisputes,
+}
+
+impl LockPolicy {
+    fn allows(&self, tx_type: &TxType) -> bool {
+        match self {
+            LockPolicy::BlockAll => false,
+            LockPolicy::AllowDisputes => !matches!(tx_type, TxType::Deposit | TxType::Withdrawal),
+        }
+    }
+}
+
+impl<S: Storage> Engine<S> {
+    pub fn new(storage: S) -> Engine<S> {
+        Engine::with_config(storage, Config::default())
+    }
+
+    pub fn with_config(storage: S, config: Config) -> Engine<S> {
+        Engine {
+            storage,
+            config,
+            unflushed: 0,
+            invariants: Invariants::new(config.invariants),
+            source: None,
+        }
+    }
+
+    // names where the rows given to `apply_row` come from, such as the input file. Row numbers
+    // start over with every input, so a ledger fed several of them needs this to tell which
+    // row 1 a journal entry is about
+    pub fn set_source(&mut self, source: impl Into<String>) {
+        self.source = Some(source.into());
+    }
+
+    pub fn apply(&mut self, tx: Transaction) -> Result<()> {
+        self.apply_at(None, tx)
+    }
+
+    // like `apply`, for a transaction read from input row `row`, which the journal records
+    pub fn apply_row(&mut self, row: u64, tx: Transaction) -> Result<()> {
+        self.apply_at(Some(row), tx)
+    }
+
+    fn apply_at(&mut self, row: Option<u64>, tx: Transaction) -> Result<()> {
+        let before = get_or_create_account(&self.storage, tx.client)?;
+        let mut acc = before.clone();
+        let processed = if acc.locked && !self.config.lock_policy.allows(&tx.tx_type) {
+            Err(RuleViolation::AccountLocked.into())
+        } else {
+            process_transaction(&self.storage, &self.config, &mut acc, &tx)
+        };
+
+        let mut entry = JournalEntry {
+            source: self.source.clone(),
+            ..JournalEntry::new(row, &tx.tx, tx.tx_type, tx.amount, &before, &acc)
+        };
+        let (record, event) = match processed {
+            Ok(changes) => changes,
+            Err(err) => {
+                // a rejection is journaled too, but anything else is not the row's doing
+                if let Some(reason) = err.reason_code() {
+                    let entry = JournalEntry {
+                        outcome: Outcome::Rejected,
+                        reason: Some(reason.to_string()),
+                        source: self.source.clone(),
+                        ..JournalEntry::new(row, &tx.tx, tx.tx_type, tx.amount, &before, &before)
+                    };
+                    self.storage.append_journal(&entry)?;
+                    self.written()?;
+                }
+                return Err(err);
+            }
+        };
+        match (&record, &event) {
+            (None, _) => entry.outcome = Outcome::Ignored,
+            (_, Some(event)) => entry.amount = event.amount,
+            _ => {}
+        }
+        if self.invariants.enabled() {
+            let storage = &self.storage;
+            self.invariants
+                .check_row(row, tx.tx_type, record.as_ref(), &before, &acc, || {
+                    total_of(storage.accounts())
+                })?;
+        }
+        self.storage.commit(&Commit {
+            account: &acc,
+            tx: record.as_ref(),
+            event: event.as_ref(),
+            journal: &entry,
+        })?;
+        self.written()
+    }
+
+    // counts a row's writes towards `Config::durability`, and flushes them when it is due
+    fn written(&mut self) -> Result<()> {
+        self.unflushed += 1;
+        let due = match self.config.durability {
+            Durability::EveryRow => true,
+            Durability::EveryN(rows) => self.unflushed >= rows,
+            Durability::AtEnd => false,
+        };
+        if due {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Applies every row read from `reader`, then flushes. Rows that are malformed or break a
+    // rule are handed to `on_reject` and the run goes on; storage and I/O errors stop it
+    pub fn process<R, F>(&mut self, reader: R, format: InputFormat, mut on_reject: F) -> Result<()>
+    where
+        R: Read,
+        F: FnMut(Rejection) -> Result<()>,
+    {
+        for row in Rows::new(reader, format)? {
+            if let Err(err) = row.parsed.and_then(|tx| self.apply_row(row.number, tx)) {
+                let rejection = Rejection::new(row.number, row.tx, row.client, &err).ok_or(err)?;
+                on_reject(rejection)?;
+            }
+        }
+        self.check_invariants()?;
+        self.flush()
+    }
+
+    // Checks every account against the ledger invariants, at the end of a run. Does nothing
+    // unless `Config::invariants` asks for it
+    pub fn check_invariants(&mut self) -> Result<()> {
+        if !self.invariants.enabled() {
+            return Ok(());
+        }
+        self.invariants.check_ledger(self.storage.accounts())
+    }
+
+    // what the invariant checks found so far, in the order they found it
+    pub fn violations(&self) -> &[Violation] {
+        self.invariants.violations()
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.storage.flush()?;
+        self.unflushed = 0;
+        Ok(())
+    }
+
+    // flushes the rows `Config::durability` hasn't flushed yet, if there are any. For callers
+    // that never reach the end of their input, such as the servers
+    pub fn flush_pending(&mut self) -> Result<()> {
+        if self.unflushed == 0 {
+            return Ok(());
+        }
+        self.flush()
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn account(&self, client_id: u16) -> Result<Option<Account>> {
+        self.storage.get_account(client_id)
+    }
+
+    pub fn transaction(&self, tx_id: &str) -> Result<Option<Transaction>> {
+        self.storage.get_transaction(tx_id)
+    }
+
+    // the disputes, resolves and chargebacks applied to a transaction, oldest first
+    pub fn history(&self, tx_id: &str) -> Result<Vec<DisputeEvent>> {
+        self.storage.events(tx_id)
+    }
+
+    // the journal entries that match `filter`, oldest first
+    pub fn journal<'a>(
+        &'a self,
+        filter: &'a JournalFilter,
+    ) -> impl Iterator<Item = Result<JournalEntry>> + 'a {
+        self.storage.journal().filter(move |entry| match entry {
+            Ok(entry) => filter.matches(entry),
+            Err(_) => true,
+        })
+    }
+
+    // accounts are yielded in client id order
+    pub fn accounts(&self) -> AccountIter<'_> {
+        self.storage.accounts()
+    }
+
+    pub fn write_accounts_csv<W: Write>(&self, writer: W) -> Result<()> {
+        output::write_accounts_csv(self.accounts(), writer)
+    }
+
+    pub fn write_accounts<W: Write>(&self, writer: W, format: OutputFormat) -> Result<()> {
+        output::write_accounts(self.accounts(), writer, format)
+    }
+}
+
+// Applies `tx` to `acc` and returns the transaction record and dispute event to store along
+// with it, if any. Nothing is written here, so the caller can commit them all together
+fn process_transaction<S: Storage>(
+    storage: &S,
+    config: &Config,
+    acc: &mut Account,
+    tx: &Transaction,
+) -> Result<(Option<Transaction>, Option<DisputeEvent>)> {
+    match storage.get_transaction(&tx.tx)? {
+        Some(mut updated_tx) => {
+            if tx.tx_type == updated_tx.tx_type
+                && tx.client == updated_tx.client
+                && tx.amount == updated_tx.amount
+            {
+                return Ok((None, None)); // Idempotent transaction, nothing to do
+            }
+
+            // a client can only dispute, resolve or charge back its own transactions,
+            // otherwise the hold would land on the wrong account
+            let references_other = matches!(
+                tx.tx_type,
+                TxType::Dispute | TxType::Resolve | TxType::Chargeback
+            );
+            if references_other && tx.client != updated_tx.client {
+                return Err(RuleViolation::ClientMismatch.into());
+            }
+
+            if tx.tx_type == TxType::Dispute
+                && updated_tx.tx_type == TxType::Withdrawal
+                && config.withdrawal_disputes == WithdrawalDisputePolicy::Reject
+            {
+                return Err(RuleViolation::WithdrawalDispute.into());
+            }
+
+            match tx.tx_type {
+                // a deposit or withdrawal reusing an id would change the balance without a
+                // record of its own to back it
+                TxType::Deposit | TxType::Withdrawal => {
+                    return Err(RuleViolation::DuplicateTx.into())
+                }
+                TxType::Dispute => updated_tx.dispute(acc)?,
+                TxType::Resolve => updated_tx.resolve(acc)?,
+                TxType::Chargeback => updated_tx.chargeback(acc)?,
+            }
+
+            // the event goes into its own keyspace, the transaction keeps its id
+            let seq = storage.events(&tx.tx)?.len() as u32 + 1;
+            let event = DisputeEvent {
+                tx: updated_tx.tx.clone(),
+                seq,
+                event: tx.tx_type,
+                client: tx.client,
+                amount: updated_tx.amount,
+                state: updated_tx.state,
+            };
+            Ok((Some(updated_tx), Some(event)))
+        }
+        None => {
+            match tx.tx_type {
+                TxType::Deposit => tx.deposit(acc)?,
+                TxType::Withdrawal => tx.withdrawal(acc)?,
+                TxType::Dispute | TxType::Resolve | TxType::Chargeback => {
+                    return Err(RuleViolation::UnknownTx.into())
+                }
+            }
+            // whatever state the row came with, a new transaction starts undisputed
+            let record = Transaction {
+                state: TxState::Processed,
+                ..tx.clone()
+            };
+            Ok((Some(record), None))
+        }
+    }
+}
+
+fn total_of(accounts: AccountIter<'_>) -> Result<Amount> {
+    let mut total = Amount::ZERO;
+    for account in accounts {
+        total = total.checked_add(account?.total)?;
+    }
+    Ok(total)
+}
+
+fn get_or_create_account<S: Storage>(storage: &S, client_id: u16) -> Result<Account> {
+    // for each transaction, one account fetched or created
+    // check if transaction with same tx (id) already stored
+    match storage.get_account(client_id)? {
+        Some(acc) => Ok(acc),
+        None => Ok(Account::new(client_id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::error::Error;
+    use crate::storage::{MemoryStorage, SledStorage};
+    use crate::test_util::{amt, deposit, TestStorage};
+    use crate::transaction::TxState;
+    use csv::{ReaderBuilder, Trim};
+    use std::io::{self, Cursor};
+
+    #[test]
+    fn test_process_transactions_in_memory() {
+        // Sample CSV data in memory
+        let csv_data = "\
+            type,client,tx,amount\n\
+            deposit,1,tx1,100.0\n\
+            withdrawal,1,tx2,50.0\n";
+
+        let mut engine = Engine::new(MemoryStorage::new());
+
+        let mut csv_reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .has_headers(true)
+            .from_reader(Cursor::new(csv_data));
+
+        for result in csv_reader.deserialize::<Transaction>() {
+            engine.apply(result.unwrap()).unwrap();
+        }
+
+        // Check if account data is updated correctly
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, amt("50.0"));
+        assert_eq!(account.total, amt("50.0"));
+        assert_eq!(account.held, amt("0.0"));
+    }
+
+    #[test]
+    fn test_get_or_create_account_in_memory() {
+        let mut storage = MemoryStorage::new();
+
+        // Creating a new account
+        let account = get_or_create_account(&storage, 1).unwrap();
+        assert_eq!(account.id, 1);
+        assert_eq!(account.total, amt("0.0"));
+        assert_eq!(account.available, amt("0.0"));
+        assert_eq!(account.held, amt("0.0"));
+        assert!(!account.locked);
+
+        // Fetching an existing account
+        storage.insert_account(&account).unwrap();
+        let fetched_account = get_or_create_account(&storage, 1).unwrap();
+        assert_eq!(fetched_account.id, 1);
+    }
+
+    #[test]
+    fn test_accounts_iterates_in_client_order() {
+        let mut engine = Engine::new(MemoryStorage::new());
+        for (client, tx) in [(3, "1"), (1, "2"), (2, "3")] {
+            engine.apply(deposit(client, tx, "1.0")).unwrap();
+        }
+
+        let ids: Vec<u16> = engine.accounts().map(|acc| acc.unwrap().id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_output_db_as_csv_in_memory() {
+        let mut engine = Engine::new(MemoryStorage::new());
+        engine.apply(deposit(1, "1", "100.0")).unwrap();
+
+        // Redirect output to a buffer
+        let mut buffer = Vec::new();
+        engine.write_accounts_csv(&mut buffer).unwrap();
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains("client,available,held,total,locked"));
+        assert!(output.contains("1,100.0000,0.0000,100.0000,false"));
+    }
+
+    #[test]
+    fn test_rejected_rows_are_rule_errors() {
+        let mut engine = Engine::new(MemoryStorage::new());
+        let dispute = Transaction {
+            tx_type: TxType::Dispute,
+            client: 1,
+            tx: "1".to_string(),
+            amount: Amount::ZERO,
+            state: TxState::Processed,
+        };
+        assert!(matches!(
+            engine.apply(dispute),
+            Err(Error::Rule(RuleViolation::UnknownTx))
+        ));
+
+        let withdrawal = Transaction {
+            tx_type: TxType::Withdrawal,
+            client: 1,
+            tx: "2".to_string(),
+            amount: amt("1.0"),
+            state: TxState::Processed,
+        };
+        assert!(matches!(
+            engine.apply(withdrawal),
+            Err(Error::Rule(RuleViolation::InsufficientFunds))
+        ));
+        // rejected rows leave no trace behind, other than their journal entry
+        assert!(engine.transaction("2").unwrap().is_none());
+        assert!(engine.account(1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_journal_records_every_outcome() {
+        let csv_data = "\
+            type,client,tx,amount\n\
+            deposit,1,1,10.0\n\
+            bogus,1,2,1.0\n\
+            withdrawal,1,3,20.0\n\
+            deposit,2,4,5.0\n\
+            dispute,1,1,\n\
+            deposit,1,1,10.0\n";
+
+        let mut engine = Engine::new(SledStorage::temporary().unwrap());
+        engine
+            .process(Cursor::new(csv_data), InputFormat::Csv, |_| Ok(()))
+            .unwrap();
+
+        let all = JournalFilter::default();
+        let entries: Vec<JournalEntry> = engine.journal(&all).map(|e| e.unwrap()).collect();
+        let summary: Vec<(u64, Option<u64>, Outcome, Option<&str>)> = entries
+            .iter()
+            .map(|e| (e.seq, e.row, e.outcome, e.reason.as_deref()))
+            .collect();
+        // the row that doesn't parse never reaches the engine
+        assert_eq!(
+            summary,
+            vec![
+                (1, Some(1), Outcome::Applied, None),
+                (2, Some(3), Outcome::Rejected, Some("INSUFFICIENT_FUNDS")),
+                (3, Some(4), Outcome::Applied, None),
+                (4, Some(5), Outcome::Applied, None),
+                (5, Some(6), Outcome::Ignored, None),
+            ]
+        );
+
+        let dispute = &entries[3];
+        assert_eq!(dispute.amount, amt("10.0"));
+        assert_eq!(
+            (dispute.available_before, dispute.held_before),
+            (amt("10.0"), amt("0.0"))
+        );
+        assert_eq!(
+            (dispute.available_after, dispute.held_after),
+            (amt("0.0"), amt("10.0"))
+        );
+        assert_eq!(entries[1].total_before, entries[1].total_after);
+
+        let by_client = JournalFilter {
+            client: Some(2),
+            ..JournalFilter::default()
+        };
+        let seqs: Vec<u64> = engine.journal(&by_client).map(|e| e.unwrap().seq).collect();
+        assert_eq!(seqs, vec![3]);
+        let by_tx = JournalFilter {
+            tx: Some("1".to_string()),
+            ..JournalFilter::default()
+        };
+        let seqs: Vec<u64> = engine.journal(&by_tx).map(|e| e.unwrap().seq).collect();
+        assert_eq!(seqs, vec![1, 4, 5]);
+    }
+
+    #[test]
+    fn test_journal_records_the_source_of_rows() {
+        let mut engine = Engine::new(MemoryStorage::new());
+        engine.set_source("day1.csv");
+        engine.apply_row(1, deposit(1, "1", "1.0")).unwrap();
+        engine.set_source("day2.csv");
+        engine.apply_row(1, deposit(1, "2", "1.0")).unwrap();
+        let _ = engine.apply_row(2, row(TxType::Resolve, 1, "2"));
+
+        let sources: Vec<(Option<u64>, Option<String>)> = engine
+            .journal(&JournalFilter::default())
+            .map(|e| e.map(|e| (e.row, e.source)).unwrap())
+            .collect();
+        let source = |name: &str| Some(name.to_string());
+        assert_eq!(
+            sources,
+            vec![
+                (Some(1), source("day1.csv")),
+                (Some(1), source("day2.csv")),
+                (Some(2), source("day2.csv")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invariants_checked_per_row_and_at_the_end() {
+        let csv_data = "\
+            type,client,tx,amount\n\
+            deposit,2,1,1.0\n\
+            deposit,1,2,1.0\n";
+        // client 1 is already off by one before the run starts
+        let corrupted = || {
+            let mut storage = MemoryStorage::new();
+            let mut account = Account::new(1);
+            account.available = amt("5.0");
+            account.total = amt("4.0");
+            storage.insert_account(&account).unwrap();
+            storage
+        };
+        let config = |invariants| Config {
+            invariants,
+            ..Config::default()
+        };
+
+        let mut engine = Engine::with_config(corrupted(), config(InvariantCheck::Report));
+        engine
+            .process(Cursor::new(csv_data), InputFormat::Csv, |_| Ok(()))
+            .unwrap();
+        let rows: Vec<Option<u64>> = engine.violations().iter().map(|v| v.row).collect();
+        assert_eq!(rows, vec![Some(2), None]);
+        assert_eq!(engine.account(1).unwrap().unwrap().total, amt("5.0"));
+
+        let mut engine = Engine::with_config(corrupted(), config(InvariantCheck::Strict));
+        let result = engine.process(Cursor::new(csv_data), InputFormat::Csv, |_| Ok(()));
+        assert!(matches!(
+            result,
+            Err(Error::Invariant(Violation { row: Some(2), .. }))
+        ));
+        // the row that broke it is not committed
+        assert_eq!(engine.account(1).unwrap().unwrap().total, amt("4.0"));
+        assert_eq!(engine.account(2).unwrap().unwrap().total, amt("1.0"));
+    }
+
+    #[test]
+    fn test_process_reads_any_source() {
+        let csv_data = "\
+            type,client,tx,amount\n\
+            deposit,1,1,10.0\n\
+            bogus,1,2,1.0\n\
+            withdrawal,1,3,20.0\n\
+            withdrawal,1,4,4.0\n";
+
+        let mut engine = Engine::new(MemoryStorage::new());
+        let mut rejected = Vec::new();
+        engine
+            .process(Cursor::new(csv_data), InputFormat::Csv, |rejection| {
+                rejected.push((rejection.row, rejection.reason));
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            rejected,
+            vec![(2, "PARSE_ERROR"), (3, "INSUFFICIENT_FUNDS")]
+        );
+        assert_eq!(engine.account(1).unwrap().unwrap().available, amt("6.0"));
+    }
+
+    #[test]
+    fn test_reused_tx_id_rejected() {
+        let mut engine = Engine::new(MemoryStorage::new());
+        engine.apply(deposit(1, "1", "10.0")).unwrap();
+        // the exact same row again is a harmless replay
+        engine.apply(deposit(1, "1", "10.0")).unwrap();
+
+        for reused in [deposit(1, "1", "5.0"), deposit(2, "1", "10.0")] {
+            assert!(matches!(
+                engine.apply(reused),
+                Err(Error::Rule(RuleViolation::DuplicateTx))
+            ));
+        }
+        assert_eq!(engine.account(1).unwrap().unwrap().total, amt("10.0"));
+        assert!(engine.account(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cross_client_references_rejected() {
+        // rows taken from data.csv, where other clients reference the deposits of 42 and 40
+        let csv_data = "\
+            type,client,tx,amount\n\
+            deposit,42,1,733.38\n\
+            dispute,45,1,\n\
+            chargeback,52,1,\n\
+            chargeback,4,1,\n\
+            resolve,91,1,\n\
+            deposit,40,16,428.34\n\
+            dispute,31,16,\n\
+            chargeback,31,16,\n";
+
+        let mut engine = Engine::new(MemoryStorage::new());
+        let mut csv_reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(Cursor::new(csv_data));
+
+        let mut mismatches = 0;
+        for result in csv_reader.deserialize::<Transaction>() {
+            match engine.apply(result.unwrap()) {
+                Ok(()) => {}
+                Err(Error::Rule(RuleViolation::ClientMismatch)) => mismatches += 1,
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+        assert_eq!(mismatches, 6);
+
+        // the owners keep their funds available and nothing lands on the other accounts
+        let owner = engine.account(42).unwrap().unwrap();
+        assert_eq!(owner.available, amt("733.38"));
+        assert_eq!(owner.held, amt("0.0"));
+        assert!(!owner.locked);
+        let owner = engine.account(40).unwrap().unwrap();
+        assert_eq!(owner.available, amt("428.34"));
+        assert!(!owner.locked);
+        for client in [45, 52, 4, 91, 31] {
+            assert!(engine.account(client).unwrap().is_none());
+        }
+        assert_eq!(
+            engine.transaction("1").unwrap().unwrap().state,
+            TxState::Processed
+        );
+    }
+
+    #[test]
+    fn test_owner_can_still_dispute() {
+        let mut engine = Engine::new(MemoryStorage::new());
+        engine.apply(deposit(42, "1", "733.38")).unwrap();
+        engine
+            .apply(Transaction {
+                tx_type: TxType::Dispute,
+                client: 42,
+                tx: "1".to_string(),
+                amount: Amount::ZERO,
+                state: TxState::Processed,
+            })
+            .unwrap();
+
+        let owner = engine.account(42).unwrap().unwrap();
+        assert_eq!(owner.available, amt("0.0"));
+        assert_eq!(owner.held, amt("733.38"));
+    }
+
+    fn row(tx_type: TxType, client: u16, tx: &str) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx: tx.to_string(),
+            amount: Amount::ZERO,
+            state: TxState::Processed,
+        }
+    }
+
+    // client 1 gets a chargeback on tx 1 and still has tx 2 to dispute
+    fn locked_engine(config: Config) -> Engine<MemoryStorage> {
+        let mut engine = Engine::with_config(MemoryStorage::new(), config);
+        engine.apply(deposit(1, "1", "10.0")).unwrap();
+        engine.apply(deposit(1, "2", "5.0")).unwrap();
+        engine.apply(row(TxType::Dispute, 1, "1")).unwrap();
+        engine.apply(row(TxType::Chargeback, 1, "1")).unwrap();
+        assert!(engine.account(1).unwrap().unwrap().locked);
+        engine
+    }
+
+    #[test]
+    fn test_locked_account_blocks_everything_by_default() {
+        let mut engine = locked_engine(Config::default());
+        let withdrawal = Transaction {
+            tx_type: TxType::Withdrawal,
+            amount: amt("1.0"),
+            ..row(TxType::Withdrawal, 1, "4")
+        };
+
+        for tx in [
+            deposit(1, "3", "1.0"),
+            withdrawal,
+            row(TxType::Dispute, 1, "2"),
+        ] {
+            assert!(matches!(
+                engine.apply(tx),
+                Err(Error::Rule(RuleViolation::AccountLocked))
+            ));
+        }
+
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, amt("5.0"));
+        assert_eq!(account.held, amt("0.0"));
+        assert!(engine.transaction("3").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_locked_account_allowing_disputes() {
+        let config = Config {
+            lock_policy: LockPolicy::AllowDisputes,
+            ..Config::default()
+        };
+        let mut engine = locked_engine(config);
+
+        assert!(matches!(
+            engine.apply(deposit(1, "3", "1.0")),
+            Err(Error::Rule(RuleViolation::AccountLocked))
+        ));
+        engine.apply(row(TxType::Dispute, 1, "2")).unwrap();
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.held, amt("5.0"));
+
+        engine.apply(row(TxType::Resolve, 1, "2")).unwrap();
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, amt("5.0"));
+        assert_eq!(account.held, amt("0.0"));
+    }
+
+    #[test]
+    fn test_dispute_state_is_persisted() {
+        let mut engine = Engine::new(SledStorage::temporary().unwrap());
+        engine.apply(deposit(1, "1", "10.0")).unwrap();
+        engine.apply(row(TxType::Dispute, 1, "1")).unwrap();
+        assert_eq!(
+            engine.transaction("1").unwrap().unwrap().state,
+            TxState::Disputed
+        );
+
+        // a second dispute is rejected instead of holding the amount twice
+        assert!(matches!(
+            engine.apply(row(TxType::Dispute, 1, "1")),
+            Err(Error::Rule(RuleViolation::AlreadyDisputed(
+                TxState::Disputed
+            )))
+        ));
+        engine.apply(row(TxType::Chargeback, 1, "1")).unwrap();
+        assert_eq!(
+            engine.transaction("1").unwrap().unwrap().state,
+            TxState::ChargedBack
+        );
+
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.held, amt("0.0"));
+        assert_eq!(account.total, amt("0.0"));
+    }
+
+    #[test]
+    fn test_dispute_history() {
+        let mut engine = Engine::new(SledStorage::temporary().unwrap());
+        engine.apply(deposit(1, "5", "10.0")).unwrap();
+        // an id that looks like a dispute record of "5" is just another transaction
+        engine.apply(deposit(1, "5-d", "3.0")).unwrap();
+        engine.apply(row(TxType::Dispute, 1, "5")).unwrap();
+        engine.apply(row(TxType::Resolve, 1, "5")).unwrap();
+        // rejected events are not part of the history
+        assert!(engine.apply(row(TxType::Dispute, 1, "5")).is_err());
+
+        let history = engine.history("5").unwrap();
+        let steps: Vec<(u32, TxType, TxState)> = history
+            .iter()
+            .map(|event| (event.seq, event.event, event.state))
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (1, TxType::Dispute, TxState::Disputed),
+                (2, TxType::Resolve, TxState::Resolved),
+            ]
+        );
+        assert!(history.iter().all(|event| event.amount == amt("10.0")));
+
+        assert!(engine.history("5-d").unwrap().is_empty());
+        let other = engine.transaction("5-d").unwrap().unwrap();
+        assert_eq!(
+            (other.amount, other.state),
+            (amt("3.0"), TxState::Processed)
+        );
+        assert_eq!(engine.account(1).unwrap().unwrap().total, amt("13.0"));
+    }
+
+    fn withdrawn_engine(config: Config) -> Engine<MemoryStorage> {
+        let mut engine = Engine::with_config(MemoryStorage::new(), config);
+        engine.apply(deposit(1, "1", "100.0")).unwrap();
+        let withdrawal = Transaction {
+            tx_type: TxType::Withdrawal,
+            amount: amt("40.0"),
+            ..row(TxType::Withdrawal, 1, "2")
+        };
+        engine.apply(withdrawal).unwrap();
+        engine
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_rejected_by_default() {
+        let mut engine = withdrawn_engine(Config::default());
+
+        assert!(matches!(
+            engine.apply(row(TxType::Dispute, 1, "2")),
+            Err(Error::Rule(RuleViolation::WithdrawalDispute))
+        ));
+        // nothing to resolve or charge back afterwards
+        for tx_type in [TxType::Resolve, TxType::Chargeback] {
+            assert!(matches!(
+                engine.apply(row(tx_type, 1, "2")),
+                Err(Error::Rule(RuleViolation::NotDisputed))
+            ));
+        }
+
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, amt("60.0"));
+        assert_eq!(account.held, amt("0.0"));
+        assert_eq!(account.total, amt("60.0"));
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_provisional_credit() {
+        let config = Config {
+            withdrawal_disputes: WithdrawalDisputePolicy::ProvisionalCredit,
+            ..Config::default()
+        };
+
+        let mut engine = withdrawn_engine(config);
+        engine.apply(row(TxType::Dispute, 1, "2")).unwrap();
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, amt("60.0"));
+        assert_eq!(account.held, amt("40.0"));
+        assert_eq!(account.total, amt("100.0"));
+
+        engine.apply(row(TxType::Resolve, 1, "2")).unwrap();
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, amt("60.0"));
+        assert_eq!(account.held, amt("0.0"));
+        assert_eq!(account.total, amt("60.0"));
+
+        let mut engine = withdrawn_engine(config);
+        engine.apply(row(TxType::Dispute, 1, "2")).unwrap();
+        engine.apply(row(TxType::Chargeback, 1, "2")).unwrap();
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, amt("100.0"));
+        assert_eq!(account.held, amt("0.0"));
+        assert_eq!(account.total, amt("100.0"));
+        assert!(account.locked);
+    }
+
+    fn flushes_after_seven_rows(durability: Durability) -> (usize, usize) {
+        let config = Config {
+            durability,
+            ..Config::default()
+        };
+        let mut engine = Engine::with_config(TestStorage::default(), config);
+        for tx in 1..=7 {
+            engine.apply(deposit(1, &tx.to_string(), "1.0")).unwrap();
+        }
+        // rejected rows are journaled, so they count towards a batch too
+        let _ = engine.apply(row(TxType::Dispute, 1, "8"));
+
+        let before_end = engine.storage.flushes;
+        engine.flush().unwrap();
+        (before_end, engine.storage.flushes)
+    }
+
+    #[test]
+    fn test_flush_pending() {
+        let config = Config {
+            durability: Durability::AtEnd,
+            ..Config::default()
+        };
+        let mut engine = Engine::with_config(TestStorage::default(), config);
+        engine.flush_pending().unwrap();
+        assert_eq!(engine.storage.flushes, 0);
+
+        engine.apply(deposit(1, "1", "1.0")).unwrap();
+        engine.flush_pending().unwrap();
+        engine.flush_pending().unwrap();
+        assert_eq!(engine.storage.flushes, 1);
+
+        // a failed flush leaves the rows pending for the next one
+        engine.apply(deposit(1, "2", "1.0")).unwrap();
+        engine.storage.flush_hook = || Err(Error::Io(io::Error::other("disk full")));
+        assert!(engine.flush_pending().is_err());
+        engine.storage.flush_hook = || Ok(());
+        engine.flush_pending().unwrap();
+        engine.flush_pending().unwrap();
+        assert_eq!(engine.storage.flushes, 3);
+    }
+
+    #[test]
+    fn test_durability_flushes() {
+        assert_eq!(flushes_after_seven_rows(Durability::EveryRow), (8, 9));
+        assert_eq!(flushes_after_seven_rows(Durability::EveryN(4)), (2, 3));
+        assert_eq!(flushes_after_seven_rows(Durability::AtEnd), (0, 1));
+    }
+
+    #[test]
+    fn test_unknown_type_is_a_parse_error() {
+        let csv_data = "type,client,tx,amount\ntransfer,1,1,1.0\ndeposit,1,2,1.0\n";
+        let mut csv_reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(Cursor::new(csv_data));
+
+        let results: Vec<_> = csv_reader
+            .deserialize::<Transaction>()
+            .map(|result| result.map_err(Error::from))
+            .collect();
+        assert!(matches!(results[0], Err(Error::Parse(_))));
+        assert!(results[1].is_ok());
+    }
+
+    #[test]
+    fn test_sled_and_memory_storage_agree() {
+        let mut memory = Engine::new(MemoryStorage::new());
+        let mut sled = Engine::new(SledStorage::temporary().unwrap());
+        let rows = [
+            deposit(2, "1", "10.5"),
+            deposit(1, "2", "3.25"),
+            Transaction {
+                tx_type: TxType::Dispute,
+                client: 2,
+                tx: "1".to_string(),
+                amount: Amount::ZERO,
+                state: TxState::Processed,
+            },
+        ];
+        for tx in rows {
+            memory.apply(tx.clone()).unwrap();
+            sled.apply(tx).unwrap();
+        }
+
+        let mut memory_csv = Vec::new();
+        let mut sled_csv = Vec::new();
+        memory.write_accounts_csv(&mut memory_csv).unwrap();
+        sled.write_accounts_csv(&mut sled_csv).unwrap();
+        assert_eq!(memory_csv, sled_csv);
+    }
+}
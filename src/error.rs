@@ -0,0 +1,169 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::amount::AmountError;
+use crate::invariants::Violation;
+use crate::transaction::TxState;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    // a row that could not be read into a `Transaction`; the run can carry on with the next one
+    Parse(String),
+    // the row was read fine, but applying it would break a business rule
+    Rule(RuleViolation),
+    Storage(sled::Error),
+    Serialization(serde_json::Error),
+    Io(io::Error),
+    // the ledger in this directory is open in another process
+    Locked(PathBuf),
+    // a ledger invariant broke while checking them strictly
+    Invariant(Violation),
+    // settings that can't work, such as `generate` rates outside 0..=1
+    Config(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleViolation {
+    InsufficientFunds,
+    UnknownTx,
+    DuplicateTx,
+    NotDisputed,
+    // the referenced transaction has already been through a dispute, and is in the given state
+    AlreadyDisputed(TxState),
+    ClientMismatch,
+    WithdrawalDispute,
+    AccountLocked,
+    Overflow,
+}
+
+impl Error {
+    // machine-readable code for errors caused by a single row, `None` for fatal ones
+    pub fn reason_code(&self) -> Option<&'static str> {
+        match self {
+            Error::Parse(_) => Some("PARSE_ERROR"),
+            Error::Rule(violation) => Some(violation.code()),
+            Error::Storage(_)
+            | Error::Serialization(_)
+            | Error::Io(_)
+            | Error::Locked(_)
+            | Error::Invariant(_)
+            | Error::Config(_) => None,
+        }
+    }
+}
+
+impl RuleViolation {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RuleViolation::InsufficientFunds => "INSUFFICIENT_FUNDS",
+            RuleViolation::UnknownTx => "UNKNOWN_TX",
+            RuleViolation::DuplicateTx => "DUPLICATE_TX",
+            RuleViolation::NotDisputed => "NOT_DISPUTED",
+            RuleViolation::AlreadyDisputed(_) => "ALREADY_DISPUTED",
+            RuleViolation::ClientMismatch => "CLIENT_MISMATCH",
+            RuleViolation::WithdrawalDispute => "WITHDRAWAL_DISPUTE",
+            RuleViolation::AccountLocked => "ACCOUNT_LOCKED",
+            RuleViolation::Overflow => "OVERFLOW",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(message) => write!(f, "Invalid transaction: {}", message),
+            Error::Rule(violation) => write!(f, "Transaction rejected: {}", violation),
+            Error::Storage(e) => write!(f, "Storage error: {}", e),
+            Error::Serialization(e) => write!(f, "Serialization error: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Locked(dir) => write!(
+                f,
+                "Ledger in {} is in use by another process",
+                dir.display()
+            ),
+            Error::Invariant(violation) => write!(f, "Invariant violated: {}", violation),
+            Error::Config(message) => write!(f, "Invalid configuration: {}", message),
+        }
+    }
+}
+
+impl fmt::Display for RuleViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            RuleViolation::InsufficientFunds => "insufficient available funds",
+            RuleViolation::UnknownTx => "referenced transaction does not exist",
+            RuleViolation::DuplicateTx => "transaction id is already taken",
+            RuleViolation::NotDisputed => "referenced transaction is not under dispute",
+            RuleViolation::AlreadyDisputed(state) => {
+                return write!(f, "referenced transaction was already disputed ({})", state)
+            }
+            RuleViolation::ClientMismatch => "referenced transaction belongs to another client",
+            RuleViolation::WithdrawalDispute => "withdrawals cannot be disputed",
+            RuleViolation::AccountLocked => "account is locked",
+            RuleViolation::Overflow => "amount overflow",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Storage(e) => Some(e),
+            Error::Serialization(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Parse(_)
+            | Error::Rule(_)
+            | Error::Locked(_)
+            | Error::Invariant(_)
+            | Error::Config(_) => None,
+        }
+    }
+}
+
+impl From<RuleViolation> for Error {
+    fn from(violation: RuleViolation) -> Error {
+        Error::Rule(violation)
+    }
+}
+
+// overflowing arithmetic is a rejected operation, anything else is a malformed amount
+impl From<AmountError> for Error {
+    fn from(err: AmountError) -> Error {
+        match err {
+            AmountError::Overflow => Error::Rule(RuleViolation::Overflow),
+            _ => Error::Parse(err.to_string()),
+        }
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(err: sled::Error) -> Error {
+        Error::Storage(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::Serialization(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Error {
+        let message = err.to_string();
+        match err.into_kind() {
+            csv::ErrorKind::Io(e) => Error::Io(e),
+            _ => Error::Parse(message),
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use rand::seq::IndexedRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::amount::Amount;
+use crate::error::{Error, Result};
+use crate::input::COLUMNS;
+
+// deposits kept around for later disputes. Once full, new deposits replace random old ones, so
+// disputes mostly name recent transactions and memory stays flat however many rows are written
+const DISPUTABLE: usize = 10_000;
+// how many rows after its dispute a resolve or chargeback can come
+const SETTLE_WITHIN: u64 = 100;
+// deposits are between 0.01 and this many cents
+const MAX_DEPOSIT_CENTS: i64 = 100_000;
+
+// What `generate` writes. The same settings always produce the same rows
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratorConfig {
+    // client ids go from 1 to this
+    pub clients: u16,
+    pub rows: u64,
+    // share of rows that dispute an earlier deposit of the same client
+    pub dispute_rate: f64,
+    // share of disputes that are later resolved, and that are later charged back. The rest
+    // are left open
+    pub resolve_rate: f64,
+    pub chargeback_rate: f64,
+    // share of rows that don't parse: unknown types, malformed amounts, out of range clients...
+    pub invalid_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> GeneratorConfig {
+        GeneratorConfig {
+            clients: 100,
+            rows: 1000,
+            dispute_rate: 0.02,
+            resolve_rate: 0.6,
+            chargeback_rate: 0.2,
+            invalid_rate: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+// A deposit that can still be disputed
+#[derive(Clone, Copy)]
+struct Deposit {
+    client: u16,
+    tx: u64,
+    cents: i64,
+}
+
+// A dispute that is going to be resolved or charged back
+struct Settlement {
+    kind: &'static str,
+    deposit: Deposit,
+}
+
+struct Generator {
+    config: GeneratorConfig,
+    rng: ChaCha8Rng,
+    next_tx: u64,
+    // what each client has available, in cents. It goes negative when a deposit that was
+    // already withdrawn is disputed
+    balances: Vec<i64>,
+    locked: Vec<bool>,
+    // the clients new rows go to, while there are any
+    unlocked: Vec<u16>,
+    disputable: Vec<Deposit>,
+    // by the row they are due at
+    settlements: BTreeMap<u64, Vec<Settlement>>,
+}
+
+// Writes `config.rows` transaction rows, after a header, as CSV that `Transaction` reads.
+// Deposits and withdrawals get increasing tx ids. Disputes only name deposits of the same
+// client that were never disputed before, resolves and chargebacks only disputes still open,
+// and withdrawals never take more than is available. So apart from the invalid rows, every row
+// applies, until every client is locked.
+pub fn generate<W: Write>(config: &GeneratorConfig, writer: W) -> Result<()> {
+    validate(config)?;
+    let mut generator = Generator {
+        config: config.clone(),
+        rng: ChaCha8Rng::seed_from_u64(config.seed),
+        next_tx: 1,
+        balances: vec![0; usize::from(config.clients) + 1],
+        locked: vec![false; usize::from(config.clients) + 1],
+        unlocked: (1..=config.clients).collect(),
+        disputable: Vec::new(),
+        settlements: BTreeMap::new(),
+    };
+
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(COLUMNS)?;
+    for row in 1..=config.rows {
+        wtr.write_record(generator.row(row))?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn validate(config: &GeneratorConfig) -> Result<()> {
+    let rates = [
+        ("dispute rate", config.dispute_rate),
+        ("resolve rate", config.resolve_rate),
+        ("chargeback rate", config.chargeback_rate),
+        ("invalid rate", config.invalid_rate),
+    ];
+    let invalid = |message: String| Err(Error::Config(message));
+    for (name, rate) in rates {
+        if !(0.0..=1.0).contains(&rate) {
+            return invalid(format!("{} must be between 0 and 1, got {}", name, rate));
+        }
+    }
+    if config.dispute_rate + config.invalid_rate > 1.0 {
+        return invalid("dispute and invalid rates add up to more than 1".to_string());
+    }
+    if config.resolve_rate + config.chargeback_rate > 1.0 {
+        return invalid("resolve and chargeback rates add up to more than 1".to_string());
+    }
+    if config.clients == 0 {
+        return invalid("there must be at least one client".to_string());
+    }
+    Ok(())
+}
+
+impl Generator {
+    fn row(&mut self, row: u64) -> [String; 4] {
+        if let Some(Settlement { kind, deposit }) = self.due(row) {
+            return [
+                kind.to_string(),
+                deposit.client.to_string(),
+                deposit.tx.to_string(),
+                String::new(),
+            ];
+        }
+
+        let roll: f64 = self.rng.random();
+        if roll < self.config.invalid_rate {
+            return self.invalid();
+        }
+        if roll < self.config.invalid_rate + self.config.dispute_rate {
+            if let Some(dispute) = self.dispute(row) {
+                return dispute;
+            }
+        }
+        self.transfer()
+    }
+
+    // the next settlement due by `row`, skipping those of clients locked since their dispute
+    fn due(&mut self, row: u64) -> Option<Settlement> {
+        loop {
+            let mut entry = self.settlements.first_entry()?;
+            if *entry.key() > row {
+                return None;
+            }
+            let settlement = entry.get_mut().pop();
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+            let Some(settlement) = settlement else {
+                continue;
+            };
+            let client = settlement.deposit.client;
+            if self.locked[usize::from(client)] {
+                continue;
+            }
+            // a resolve releases the held funds, a chargeback takes them away for good
+            if settlement.kind == "resolve" {
+                self.balances[usize::from(client)] += settlement.deposit.cents;
+            } else {
+                self.lock(client);
+            }
+            return Some(settlement);
+        }
+    }
+
+    fn dispute(&mut self, row: u64) -> Option<[String; 4]> {
+        // deposits of clients locked in the meantime can't be disputed anymore
+        let deposit = loop {
+            if self.disputable.is_empty() {
+                return None;
+            }
+            let index = self.rng.random_range(0..self.disputable.len());
+            let deposit = self.disputable.swap_remove(index);
+            if !self.locked[usize::from(deposit.client)] {
+                break deposit;
+            }
+        };
+        self.balances[usize::from(deposit.client)] -= deposit.cents;
+
+        let fate: f64 = self.rng.random();
+        let kind = if fate < self.config.resolve_rate {
+            Some("resolve")
+        } else if fate < self.config.resolve_rate + self.config.chargeback_rate {
+            Some("chargeback")
+        } else {
+            None
+        };
+        if let Some(kind) = kind {
+            let due = row + self.rng.random_range(1..=SETTLE_WITHIN);
+            self.settlements
+                .entry(due)
+                .or_default()
+                .push(Settlement { kind, deposit });
+        }
+        Some([
+            "dispute".to_string(),
+            deposit.client.to_string(),
+            deposit.tx.to_string(),
+            String::new(),
+        ])
+    }
+
+    // a deposit, or a withdrawal of part of what the client has
+    fn transfer(&mut self) -> [String; 4] {
+        let client = self.client();
+        let tx = self.take_tx();
+        let balance = &mut self.balances[usize::from(client)];
+        let withdraw = *balance > 0 && self.rng.random_bool(0.4);
+        let (kind, cents) = if withdraw {
+            ("withdrawal", self.rng.random_range(1..=*balance))
+        } else {
+            ("deposit", self.rng.random_range(1..=MAX_DEPOSIT_CENTS))
+        };
+        if withdraw {
+            *balance -= cents;
+        } else {
+            *balance += cents;
+            let deposit = Deposit { client, tx, cents };
+            if self.disputable.len() < DISPUTABLE {
+                self.disputable.push(deposit);
+            } else {
+                let index = self.rng.random_range(0..DISPUTABLE);
+                self.disputable[index] = deposit;
+            }
+        }
+        [
+            kind.to_string(),
+            client.to_string(),
+            tx.to_string(),
+            Amount::from_scaled(cents * 100).to_string(),
+        ]
+    }
+
+    // a row that looks like a transaction but can't be read as one
+    fn invalid(&mut self) -> [String; 4] {
+        let client = self.client().to_string();
+        let tx = self.take_tx().to_string();
+        let kinds = [
+            ["transfer", &client, &tx, "10.00"],
+            ["deposit", &client, &tx, "12.3.4"],
+            ["deposit", &client, &tx, "1.23456"],
+            ["withdrawal", &client, &tx, "ten"],
+            ["deposit", "70000", &tx, "5.00"],
+            ["deposit", "", &tx, "5.00"],
+        ];
+        kinds
+            .choose(&mut self.rng)
+            .expect("there are invalid rows to choose from")
+            .map(str::to_string)
+    }
+
+    // a random client that isn't locked. Once every client is, their rows are rejected
+    fn client(&mut self) -> u16 {
+        match self.unlocked.choose(&mut self.rng) {
+            Some(client) => *client,
+            None => self.rng.random_range(1..=self.config.clients),
+        }
+    }
+
+    fn lock(&mut self, client: u16) {
+        self.locked[usize::from(client)] = true;
+        if let Some(index) = self.unlocked.iter().position(|open| *open == client) {
+            self.unlocked.swap_remove(index);
+        }
+    }
+
+    fn take_tx(&mut self) -> u64 {
+        let tx = self.next_tx;
+        self.next_tx += 1;
+        tx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    use crate::engine::Engine;
+    use crate::input::InputFormat;
+    use crate::storage::MemoryStorage;
+
+    fn generated(config: &GeneratorConfig) -> String {
+        let mut buffer = Vec::new();
+        generate(config, &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    // the reason codes of every rejected row, by how often they came up
+    fn rejections(csv_data: &str) -> BTreeMap<&'static str, usize> {
+        let mut reasons = BTreeMap::new();
+        let mut engine = Engine::new(MemoryStorage::new());
+        engine
+            .process(csv_data.as_bytes(), InputFormat::Csv, |rejection| {
+                *reasons.entry(rejection.reason).or_insert(0) += 1;
+                Ok(())
+            })
+            .unwrap();
+        reasons
+    }
+
+    #[test]
+    fn test_same_seed_same_rows() {
+        let config = GeneratorConfig {
+            invalid_rate: 0.1,
+            ..GeneratorConfig::default()
+        };
+        let rows = generated(&config);
+        assert_eq!(rows.lines().count(), 1001);
+        assert!(rows.starts_with("type,client,tx,amount\n"));
+        assert_eq!(rows, generated(&config));
+
+        let other = GeneratorConfig { seed: 1, ..config };
+        assert_ne!(rows, generated(&other));
+    }
+
+    #[test]
+    fn test_valid_rows_apply() {
+        let config = GeneratorConfig {
+            clients: 200,
+            rows: 5000,
+            dispute_rate: 0.1,
+            resolve_rate: 0.5,
+            chargeback_rate: 0.2,
+            ..GeneratorConfig::default()
+        };
+        let rows = generated(&config);
+        for kind in ["deposit", "withdrawal", "dispute", "resolve", "chargeback"] {
+            assert!(rows.lines().any(|row| row.starts_with(kind)), "no {}", kind);
+        }
+
+        assert_eq!(rejections(&rows), BTreeMap::new());
+    }
+
+    #[test]
+    fn test_invalid_rows_dont_parse() {
+        let config = GeneratorConfig {
+            invalid_rate: 1.0,
+            dispute_rate: 0.0,
+            ..GeneratorConfig::default()
+        };
+        let reasons = rejections(&generated(&config));
+        assert_eq!(reasons, BTreeMap::from([("PARSE_ERROR", 1000)]));
+    }
+
+    #[test]
+    fn test_rates_are_checked() {
+        let too_many = GeneratorConfig {
+            dispute_rate: 0.6,
+            invalid_rate: 0.6,
+            ..GeneratorConfig::default()
+        };
+        assert!(matches!(
+            generate(&too_many, io::sink()),
+            Err(Error::Config(_))
+        ));
+        let negative = GeneratorConfig {
+            resolve_rate: -0.1,
+            ..GeneratorConfig::default()
+        };
+        assert!(matches!(
+            generate(&negative, io::sink()),
+            Err(Error::Config(_))
+        ));
+    }
+}
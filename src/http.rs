@@ -0,0 +1,439 @@
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::engine::Engine;
+use crate::error::{Error, Result};
+use crate::input;
+use crate::parallel::lock;
+use crate::report::Rejection;
+use crate::server::flush_periodically;
+use crate::storage::Storage;
+use crate::transaction::{DisputeEvent, Transaction};
+
+// threads taking requests off the listener
+const HANDLERS: usize = 4;
+// the largest request body accepted, some ten thousand transactions
+const MAX_BODY: u64 = 1 << 20;
+
+// A JSON API over a shared ledger:
+//
+//   POST /transactions       one transaction object, or an array of them, applied in order
+//   GET  /accounts           every account, in client id order
+//   GET  /accounts/{client}  one account
+//   GET  /transactions/{tx}  one deposit or withdrawal, with its dispute state and history
+//
+// Transactions use the same fields as a CSV row (`type`, `client`, `tx`, `amount`) and
+// amounts always come back as exact decimal strings.
+pub struct HttpServer<S: Storage> {
+    server: Arc<tiny_http::Server>,
+    engine: Arc<Mutex<Engine<S>>>,
+}
+
+// What happened to one posted transaction
+#[derive(Debug, Serialize)]
+struct ItemResult {
+    tx: Option<String>,
+    client: Option<u16>,
+    applied: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+// A transaction with the disputes, resolves and chargebacks applied to it, oldest first
+#[derive(Debug, Serialize)]
+struct TransactionView {
+    #[serde(flatten)]
+    tx: Transaction,
+    history: Vec<DisputeEvent>,
+}
+
+impl<S: Storage + Send + 'static> HttpServer<S> {
+    pub fn bind<A: ToSocketAddrs>(addr: A, engine: Engine<S>) -> Result<HttpServer<S>> {
+        let listener = TcpListener::bind(addr)?;
+        let server = tiny_http::Server::from_listener(listener, None)
+            .map_err(|e| Error::Io(io::Error::other(e.to_string())))?;
+        Ok(HttpServer {
+            server: Arc::new(server),
+            engine: Arc::new(Mutex::new(engine)),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.server
+            .server_addr()
+            .to_ip()
+            .ok_or_else(|| Error::Io(io::Error::other("not listening on an IP address")))
+    }
+
+    // serves requests until the listener fails
+    pub fn run(self) -> Result<()> {
+        flush_periodically(Arc::clone(&self.engine));
+        let handlers: Vec<_> = (0..HANDLERS)
+            .map(|_| {
+                let server = Arc::clone(&self.server);
+                let engine = Arc::clone(&self.engine);
+                thread::spawn(move || -> Result<()> {
+                    loop {
+                        let request = server.recv()?;
+                        // a client that goes away before its response is sent only affects itself
+                        let _ = handle(request, &engine);
+                    }
+                })
+            })
+            .collect();
+        for handler in handlers {
+            handler
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+        }
+        Ok(())
+    }
+}
+
+fn handle<S: Storage>(mut request: Request, engine: &Mutex<Engine<S>>) -> io::Result<()> {
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let segments: Option<Vec<String>> = path
+        .trim_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect();
+
+    let (status, body) = match segments {
+        Some(segments) => {
+            let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+            route(&mut request, engine, &segments)
+        }
+        None => (400, error_body("malformed percent-encoding in the path")),
+    };
+
+    let content_type =
+        Header::from_bytes("Content-Type", "application/json").expect("valid header");
+    let response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(content_type);
+    request.respond(response)
+}
+
+fn route<S: Storage>(
+    request: &mut Request,
+    engine: &Mutex<Engine<S>>,
+    segments: &[&str],
+) -> (u16, String) {
+    match (request.method(), segments) {
+        (Method::Post, ["transactions"]) => match read_body(request) {
+            Ok(body) => post_transactions(engine, &body),
+            Err(response) => response,
+        },
+        (Method::Get, ["accounts"]) => {
+            let accounts: Result<Vec<_>> = lock(engine).accounts().collect();
+            match accounts {
+                Ok(accounts) => (200, to_body(&accounts)),
+                Err(e) => (500, error_body(e)),
+            }
+        }
+        (Method::Get, ["accounts", client]) => match client.parse::<u16>() {
+            Ok(client) => found(lock(engine).account(client), "account"),
+            Err(_) => (400, error_body("client must be a number")),
+        },
+        (Method::Get, ["transactions", tx]) => found(transaction(&lock(engine), tx), "transaction"),
+        (_, ["transactions"] | ["accounts"] | ["accounts", _] | ["transactions", _]) => {
+            (405, error_body("method not allowed"))
+        }
+        _ => (404, error_body("not found")),
+    }
+}
+
+// the body of a POST, or the response to send when it is too large or can't be read
+fn read_body(request: &mut Request) -> std::result::Result<String, (u16, String)> {
+    let too_large = || {
+        let message = format!("request body is larger than {} bytes", MAX_BODY);
+        (413, error_body(message))
+    };
+    if request
+        .body_length()
+        .is_some_and(|len| len as u64 > MAX_BODY)
+    {
+        return Err(too_large());
+    }
+    // a body sent without a length is only read up to one byte past the limit
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY + 1)
+        .read_to_string(&mut body)
+        .map_err(|e| (400, error_body(e)))?;
+    if body.len() as u64 > MAX_BODY {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
+// decodes the `%XX` escapes of a path segment, so ids with spaces, `%` or `/` can be asked
+// for. `None` when an escape is malformed or the result isn't UTF-8
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte != b'%' {
+            bytes.push(byte);
+            rest = tail;
+            continue;
+        }
+        let hex = tail.get(..2)?;
+        if !hex.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        let hex = std::str::from_utf8(hex).ok()?;
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        rest = &tail[2..];
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// every item gets a result, in the order it was posted; a rejected item doesn't stop the
+// others. A storage failure does, and leaves the rest of the batch unapplied: the response is
+// then a 500 that still carries the results of the items before it, which were committed
+fn post_transactions<S: Storage>(engine: &Mutex<Engine<S>>, body: &str) -> (u16, String) {
+    let (items, many) = match serde_json::from_str(body) {
+        Ok(Value::Array(items)) => (items, true),
+        Ok(item @ Value::Object(_)) => (vec![item], false),
+        Ok(_) => {
+            return (
+                400,
+                error_body("expected a transaction or an array of them"),
+            )
+        }
+        Err(e) => return (400, error_body(e)),
+    };
+
+    let mut engine = lock(engine);
+    let mut results = Vec::with_capacity(items.len());
+    for (index, item) in items.into_iter().enumerate() {
+        let row = input::json_row(index as u64 + 1, item);
+        let outcome = row.parsed.and_then(|tx| engine.apply(tx));
+        let result = match outcome {
+            Ok(()) => ItemResult {
+                tx: row.tx,
+                client: row.client,
+                applied: true,
+                reason: None,
+                message: None,
+            },
+            Err(err) => match Rejection::new(row.number, row.tx, row.client, &err) {
+                Some(rejection) => ItemResult {
+                    tx: rejection.tx,
+                    client: rejection.client,
+                    applied: false,
+                    reason: Some(rejection.reason),
+                    message: Some(rejection.message),
+                },
+                None => {
+                    let failed = json!({ "error": err.to_string(), "results": results });
+                    return (500, to_body(&failed));
+                }
+            },
+        };
+        results.push(result);
+    }
+
+    if many {
+        (200, to_body(&results))
+    } else {
+        (200, to_body(&results[0]))
+    }
+}
+
+fn transaction<S: Storage>(engine: &Engine<S>, tx_id: &str) -> Result<Option<TransactionView>> {
+    let Some(tx) = engine.transaction(tx_id)? else {
+        return Ok(None);
+    };
+    let history = engine.history(tx_id)?;
+    Ok(Some(TransactionView { tx, history }))
+}
+
+fn found<T: Serialize>(record: Result<Option<T>>, what: &str) -> (u16, String) {
+    match record {
+        Ok(Some(record)) => (200, to_body(&record)),
+        Ok(None) => (404, error_body(format!("{} not found", what))),
+        Err(e) => (500, error_body(e)),
+    }
+}
+
+// serialized straight from the typed value, so fields keep their declaration order
+fn to_body<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("accounts, transactions and results serialize to JSON")
+}
+
+fn error_body<E: std::fmt::Display>(err: E) -> String {
+    to_body(&json!({ "error": err.to_string() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::test_util::TestStorage;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpStream;
+
+    fn start() -> SocketAddr {
+        let server = HttpServer::bind("127.0.0.1:0", Engine::new(MemoryStorage::new())).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        addr
+    }
+
+    // a bare HTTP/1.0 client, enough for the tests
+    fn call(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.0\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line).unwrap();
+        let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut response = String::new();
+        reader.read_to_string(&mut response).unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn test_post_and_query() {
+        let addr = start();
+        let batch = r#"[
+            {"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"},
+            {"type": "withdrawal", "client": 1, "tx": 2, "amount": 20},
+            {"type": "dispute", "client": 1, "tx": 1},
+            {"type": "refund", "client": 1, "tx": 3}
+        ]"#;
+        let (status, results) = call(addr, "POST", "/transactions", batch);
+        assert_eq!(status, 200);
+        let applied: Vec<bool> = results
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["applied"].as_bool().unwrap())
+            .collect();
+        assert_eq!(applied, vec![true, false, true, false]);
+        assert_eq!(results[1]["reason"], "INSUFFICIENT_FUNDS");
+        assert_eq!(results[3]["reason"], "PARSE_ERROR");
+        assert_eq!(results[3]["tx"], "3");
+
+        let single = r#"{"type": "deposit", "client": 2, "tx": "a", "amount": 1}"#;
+        let (_, result) = call(addr, "POST", "/transactions", single);
+        assert_eq!(result["applied"], true);
+
+        let (status, account) = call(addr, "GET", "/accounts/1", "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            account,
+            json!({"client": 1, "available": "0.0000", "held": "10.5000", "total": "10.5000", "locked": false})
+        );
+        let (_, accounts) = call(addr, "GET", "/accounts", "");
+        assert_eq!(accounts.as_array().unwrap().len(), 2);
+        assert_eq!(accounts[1]["client"], 2);
+
+        let (status, tx) = call(addr, "GET", "/transactions/1", "");
+        assert_eq!(status, 200);
+        assert_eq!(tx["type"], "deposit");
+        assert_eq!(tx["state"], "disputed");
+        assert_eq!(tx["history"].as_array().unwrap().len(), 1);
+        assert_eq!(tx["history"][0]["seq"], 1);
+        assert_eq!(tx["history"][0]["type"], "dispute");
+    }
+
+    #[test]
+    fn test_tx_ids_in_paths_are_percent_decoded() {
+        let addr = start();
+        for tx in ["a b", "x/y", "100%"] {
+            let item = json!({"type": "deposit", "client": 1, "tx": tx, "amount": "1"});
+            call(addr, "POST", "/transactions", &item.to_string());
+        }
+        for (path, tx) in [
+            ("/transactions/a%20b", "a b"),
+            ("/transactions/x%2Fy", "x/y"),
+            ("/transactions/100%25", "100%"),
+        ] {
+            let (status, found) = call(addr, "GET", path, "");
+            assert_eq!(status, 200, "{}", path);
+            assert_eq!(found["tx"], tx);
+        }
+        assert_eq!(call(addr, "GET", "/transactions/100%2", "").0, 400);
+        assert_eq!(call(addr, "GET", "/transactions/%zz", "").0, 400);
+    }
+
+    #[test]
+    fn test_body_size_is_capped() {
+        let addr = start();
+        let item = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1"}"#;
+        let items = vec![item; MAX_BODY as usize / item.len() + 1];
+        let body = format!("[{}]", items.join(","));
+        assert_eq!(call(addr, "POST", "/transactions", &body).0, 413);
+    }
+
+    #[test]
+    fn test_storage_failure_keeps_earlier_results() {
+        // fails to commit anything for client 9
+        let storage = TestStorage {
+            commit_hook: |commit| match commit.account.id {
+                9 => Err(Error::Io(io::Error::other("disk full"))),
+                _ => Ok(()),
+            },
+            ..TestStorage::default()
+        };
+        let engine = Engine::new(storage);
+        let server = HttpServer::bind("127.0.0.1:0", engine).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let batch = r#"[
+            {"type": "deposit", "client": 1, "tx": 1, "amount": "1"},
+            {"type": "withdrawal", "client": 1, "tx": 2, "amount": "5"},
+            {"type": "deposit", "client": 9, "tx": 3, "amount": "1"},
+            {"type": "deposit", "client": 1, "tx": 4, "amount": "1"}
+        ]"#;
+        let (status, body) = call(addr, "POST", "/transactions", batch);
+        assert_eq!(status, 500);
+        assert_eq!(body["error"], "I/O error: disk full");
+        let applied: Vec<bool> = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["applied"].as_bool().unwrap())
+            .collect();
+        assert_eq!(applied, vec![true, false]);
+        assert_eq!(call(addr, "GET", "/transactions/4", "").0, 404);
+    }
+
+    #[test]
+    fn test_errors() {
+        let addr = start();
+        assert_eq!(call(addr, "GET", "/accounts/7", "").0, 404);
+        assert_eq!(call(addr, "GET", "/accounts/x", "").0, 400);
+        assert_eq!(call(addr, "GET", "/transactions/7", "").0, 404);
+        assert_eq!(call(addr, "POST", "/transactions", "not json").0, 400);
+        assert_eq!(call(addr, "DELETE", "/accounts", "").0, 405);
+        assert_eq!(call(addr, "GET", "/nothing", "").0, 404);
+    }
+}
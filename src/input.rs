@@ -0,0 +1,373 @@
+use std::io::{BufRead, BufReader, Read};
+
+use csv::{Reader, ReaderBuilder, StringRecord, Trim};
+use serde_json::Value;
+
+use crate::error::{Error, Result};
+use crate::transaction::Transaction;
+
+// One data row of the input, numbered from 1 after the header
+#[derive(Debug)]
+pub struct Row {
+    pub number: u64,
+    // what could be read of the row even when it doesn't parse, for reporting
+    pub tx: Option<String>,
+    pub client: Option<u16>,
+    pub parsed: Result<Transaction>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    // a header line, then one row per line
+    #[default]
+    Csv,
+    // one JSON object per line, with the same fields as a CSV row
+    Jsonl,
+}
+
+// Rows of either format, for callers that only learn the format at runtime
+pub enum Rows<R: Read> {
+    Csv(CsvRows<R>),
+    Jsonl(JsonlRows<R>),
+}
+
+// column order of rows sent without a header
+pub const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+// Reads transaction rows from any CSV source: a file, stdin, a socket...
+// A malformed row comes out as a `Row` with a `Parse` error so the caller can move on to the
+// next one. An I/O error comes out the same way but ends the iteration, nothing can be read
+// after it.
+pub struct CsvRows<R> {
+    reader: Reader<R>,
+    headers: StringRecord,
+    record: StringRecord,
+    number: u64,
+    done: bool,
+}
+
+impl<R: Read> CsvRows<R> {
+    pub fn new(reader: R) -> Result<CsvRows<R>> {
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .has_headers(true)
+            .from_reader(reader);
+        let headers = reader.headers()?.clone();
+        Ok(CsvRows {
+            reader,
+            headers,
+            record: StringRecord::new(),
+            number: 0,
+            done: false,
+        })
+    }
+
+    // for sources with no header line, where rows always come in `COLUMNS` order
+    pub fn headerless(reader: R) -> CsvRows<R> {
+        let reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .has_headers(false)
+            .from_reader(reader);
+        CsvRows {
+            reader,
+            headers: StringRecord::from(COLUMNS.to_vec()),
+            record: StringRecord::new(),
+            number: 0,
+            done: false,
+        }
+    }
+
+    fn field(&self, name: &str) -> Option<String> {
+        let index = self.headers.iter().position(|header| header == name)?;
+        self.record.get(index).map(str::to_string)
+    }
+}
+
+impl<R: Read> Iterator for CsvRows<R> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Row> {
+        if self.done {
+            return None;
+        }
+        self.number += 1;
+        let parsed = match self.reader.read_record(&mut self.record) {
+            Ok(false) => {
+                self.done = true;
+                return None;
+            }
+            Ok(true) => self
+                .record
+                .deserialize::<Transaction>(Some(&self.headers))
+                .map_err(|e| e.into()),
+            Err(e) => {
+                self.done = e.is_io_error();
+                // whatever is left in the record belongs to an earlier row
+                self.record.clear();
+                Err(e.into())
+            }
+        };
+        Some(Row {
+            number: self.number,
+            tx: self.field("tx"),
+            client: self.field("client").and_then(|client| client.parse().ok()),
+            parsed,
+        })
+    }
+}
+
+// Reads JSON Lines: one transaction object per line, numbered by line. Blank lines are
+// skipped but still counted, so row numbers match line numbers in the source. Like with CSV,
+// a line that isn't a valid transaction is a `Parse` error and an I/O error ends the rows.
+pub struct JsonlRows<R> {
+    reader: BufReader<R>,
+    line: Vec<u8>,
+    number: u64,
+    done: bool,
+}
+
+impl<R: Read> JsonlRows<R> {
+    pub fn new(reader: R) -> JsonlRows<R> {
+        JsonlRows {
+            reader: BufReader::new(reader),
+            line: Vec::new(),
+            number: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for JsonlRows<R> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Row> {
+        while !self.done {
+            self.number += 1;
+            self.line.clear();
+            match self.reader.read_until(b'\n', &mut self.line) {
+                Ok(0) => self.done = true,
+                Ok(_) if self.line.trim_ascii().is_empty() => {}
+                Ok(_) => {
+                    return Some(match serde_json::from_slice(&self.line) {
+                        Ok(value) => json_row(self.number, value),
+                        Err(e) => Row {
+                            number: self.number,
+                            tx: None,
+                            client: None,
+                            parsed: Err(Error::Parse(e.to_string())),
+                        },
+                    })
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Row {
+                        number: self.number,
+                        tx: None,
+                        client: None,
+                        parsed: Err(e.into()),
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<R: Read> Rows<R> {
+    pub fn new(reader: R, format: InputFormat) -> Result<Rows<R>> {
+        Ok(match format {
+            InputFormat::Csv => Rows::Csv(CsvRows::new(reader)?),
+            InputFormat::Jsonl => Rows::Jsonl(JsonlRows::new(reader)),
+        })
+    }
+}
+
+impl<R: Read> Iterator for Rows<R> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Row> {
+        match self {
+            Rows::Csv(rows) => rows.next(),
+            Rows::Jsonl(rows) => rows.next(),
+        }
+    }
+}
+
+// a single headerless row, such as one line received over the network
+pub fn parse_line(number: u64, line: &str) -> Row {
+    let row = CsvRows::headerless(line.as_bytes()).next();
+    let row = row.unwrap_or_else(|| Row {
+        number,
+        tx: None,
+        client: None,
+        parsed: Err(Error::Parse("empty row".to_string())),
+    });
+    Row { number, ..row }
+}
+
+// one transaction given as a JSON object. Amounts and types are read like in CSV, and since
+// JSON producers tend to send ids and amounts as numbers, those are taken as the text they
+// were written as. serde_json keeps that text (its `arbitrary_precision` feature), so a
+// numeric amount never goes through a float either
+pub fn json_row(number: u64, mut value: Value) -> Row {
+    for field in ["tx", "amount"] {
+        if let Some(Value::Number(n)) = value.get(field) {
+            let text = n.to_string();
+            value[field] = Value::String(text);
+        }
+    }
+    let tx = value.get("tx").and_then(Value::as_str).map(str::to_string);
+    let client = value.get("client").and_then(Value::as_u64);
+    Row {
+        number,
+        tx,
+        client: client.and_then(|client| u16::try_from(client).ok()),
+        parsed: serde_json::from_value(value).map_err(|e| Error::Parse(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TxType;
+    use std::io;
+
+    #[test]
+    fn test_rows_are_numbered_and_keep_going_after_bad_ones() {
+        let csv_data = "\
+            type, client, tx, amount\n\
+            deposit, 1, 1, 1.0\n\
+            refund, 2, 2, 1.0\n\
+            dispute, 1, 1,\n";
+
+        let rows: Vec<Row> = CsvRows::new(csv_data.as_bytes()).unwrap().collect();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].parsed.as_ref().unwrap().tx_type, TxType::Deposit);
+        assert!(matches!(rows[1].parsed, Err(Error::Parse(_))));
+        assert_eq!(rows[1].number, 2);
+        assert_eq!(rows[1].tx.as_deref(), Some("2"));
+        assert_eq!(rows[1].client, Some(2));
+        assert_eq!(rows[2].parsed.as_ref().unwrap().tx_type, TxType::Dispute);
+    }
+
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("connection reset"))
+        }
+    }
+
+    #[test]
+    fn test_io_error_ends_the_rows() {
+        let source = "type,client,tx,amount\n".as_bytes().chain(Broken);
+        let rows: Vec<Row> = CsvRows::new(source).unwrap().collect();
+
+        assert_eq!(rows.len(), 1);
+        assert!(matches!(rows[0].parsed, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let row = parse_line(7, "withdrawal, 3, 12, 1.5");
+        assert_eq!(row.number, 7);
+        let tx = row.parsed.unwrap();
+        assert_eq!(tx.tx_type, TxType::Withdrawal);
+        assert_eq!(tx.client, 3);
+        assert_eq!(tx.tx, "12");
+
+        assert!(matches!(parse_line(1, "").parsed, Err(Error::Parse(_))));
+        assert!(matches!(
+            parse_line(1, "deposit,x,1,1").parsed,
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_json_row() {
+        let value = serde_json::json!({"type": "deposit", "client": 2, "tx": 15, "amount": 1.25});
+        let tx = json_row(1, value).parsed.unwrap();
+        assert_eq!(tx.tx_type, TxType::Deposit);
+        assert_eq!(tx.tx, "15");
+        assert_eq!(tx.amount, "1.25".parse().unwrap());
+
+        let value = serde_json::json!({"type": "Dispute", "client": 2, "tx": "15"});
+        assert_eq!(json_row(2, value).parsed.unwrap().tx_type, TxType::Dispute);
+
+        let row = json_row(
+            3,
+            serde_json::json!({"type": "refund", "client": 9, "tx": "4"}),
+        );
+        assert!(matches!(row.parsed, Err(Error::Parse(_))));
+        assert_eq!(row.tx.as_deref(), Some("4"));
+        assert_eq!(row.client, Some(9));
+    }
+
+    #[test]
+    fn test_json_amounts_are_read_as_text() {
+        let amount = |text: &str| {
+            let line = format!(
+                r#"{{"type":"deposit","client":1,"tx":1,"amount":{}}}"#,
+                text
+            );
+            json_row(1, serde_json::from_str(&line).unwrap())
+                .parsed
+                .map(|tx| tx.amount)
+        };
+        assert!(matches!(
+            amount("1.00000000000000001"),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(amount("1e3"), Err(Error::Parse(_))));
+        assert_eq!(
+            amount("123456789012.5").unwrap(),
+            "123456789012.5".parse().unwrap()
+        );
+        assert_eq!(amount(r#""0.0001""#).unwrap(), "0.0001".parse().unwrap());
+    }
+
+    #[test]
+    fn test_jsonl_rows() {
+        let jsonl_data = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}
+
+{"type": "withdrawal", "client": 1, "tx": "2", "amount": 0.5}
+not json
+{"type": "dispute", "client": 1, "tx": 1}
+"#;
+
+        let rows: Vec<Row> = Rows::new(jsonl_data.as_bytes(), InputFormat::Jsonl)
+            .unwrap()
+            .collect();
+
+        let numbers: Vec<u64> = rows.iter().map(|row| row.number).collect();
+        assert_eq!(numbers, vec![1, 3, 4, 5]);
+        assert_eq!(rows[0].parsed.as_ref().unwrap().tx, "1");
+        assert_eq!(
+            rows[1].parsed.as_ref().unwrap().amount,
+            "0.5".parse().unwrap()
+        );
+        assert!(matches!(rows[2].parsed, Err(Error::Parse(_))));
+        assert_eq!(rows[3].parsed.as_ref().unwrap().tx_type, TxType::Dispute);
+    }
+
+    #[test]
+    fn test_jsonl_matches_csv() {
+        let csv_data = "type,client,tx,amount\ndeposit,2,7,3.25\nWithdrawal,2,8,-1\n";
+        let jsonl_data = concat!(
+            r#"{"type":"deposit","client":2,"tx":"7","amount":"3.25"}"#,
+            "\n",
+            r#"{"type":"Withdrawal","client":2,"tx":8,"amount":-1}"#,
+        );
+
+        let from_csv = Rows::new(csv_data.as_bytes(), InputFormat::Csv).unwrap();
+        let from_jsonl = Rows::new(jsonl_data.as_bytes(), InputFormat::Jsonl).unwrap();
+        for (csv_row, jsonl_row) in from_csv.zip(from_jsonl) {
+            let (a, b) = (csv_row.parsed.unwrap(), jsonl_row.parsed.unwrap());
+            assert_eq!(
+                (a.tx_type, a.client, a.tx, a.amount),
+                (b.tx_type, b.client, b.tx, b.amount)
+            );
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use std::fmt;
+
+use crate::account::Account;
+use crate::amount::Amount;
+use crate::error::{Error, Result};
+use crate::transaction::{Transaction, TxType};
+
+// Whether the engine checks the ledger's invariants as it goes:
+//
+//   - every account's total is its available plus held funds
+//   - no account holds a negative amount
+//   - the sum of all totals is what was deposited, minus what was withdrawn and charged back
+//     (plus provisional credits of disputed withdrawals)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InvariantCheck {
+    #[default]
+    Off,
+    // violations are collected, see `Engine::violations`, and the run goes on
+    Report,
+    // the first violation stops the run with `Error::Invariant`, before the row is committed
+    Strict,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invariant {
+    Balance {
+        client: u16,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+    },
+    NegativeHeld {
+        client: u16,
+        held: Amount,
+    },
+    LedgerTotal {
+        actual: Amount,
+        expected: Amount,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    // the input row that broke the invariant. `None` when it was found by the end of run check,
+    // or the transaction didn't come from an input row
+    pub row: Option<u64>,
+    pub invariant: Invariant,
+}
+
+// Tracks what the ledger should add up to while rows are applied
+#[derive(Debug, Default)]
+pub(crate) struct Invariants {
+    check: InvariantCheck,
+    // the sum of all totals, taken before the first row and moved by what every row moved
+    expected: Option<Amount>,
+    violations: Vec<Violation>,
+}
+
+impl Invariants {
+    pub(crate) fn new(check: InvariantCheck) -> Invariants {
+        Invariants {
+            check,
+            ..Invariants::default()
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.check != InvariantCheck::Off
+    }
+
+    pub(crate) fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    // checks the account a row changed. `opening` is only called once, before the first row,
+    // to get the sum of all totals the ledger started with
+    pub(crate) fn check_row<F>(
+        &mut self,
+        row: Option<u64>,
+        event: TxType,
+        record: Option<&Transaction>,
+        before: &Account,
+        after: &Account,
+        opening: F,
+    ) -> Result<()>
+    where
+        F: FnOnce() -> Result<Amount>,
+    {
+        let expected = match self.expected {
+            Some(expected) => expected,
+            None => opening()?,
+        };
+        let moved = after.total.checked_sub(before.total)?;
+        let should_move = moved_by(event, record)?;
+        let next = expected.checked_add(should_move)?;
+        self.expected = Some(next);
+
+        let mut found = account_violations(after);
+        if moved != should_move {
+            found.push(Invariant::LedgerTotal {
+                actual: expected.checked_add(moved)?,
+                expected: next,
+            });
+        }
+        self.record(row, found)
+    }
+
+    // checks every account, and that together they add up to what the rows moved
+    pub(crate) fn check_ledger<I>(&mut self, accounts: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Result<Account>>,
+    {
+        let mut found = Vec::new();
+        let mut actual = Amount::ZERO;
+        for account in accounts {
+            let account = account?;
+            actual = actual.checked_add(account.total)?;
+            found.extend(account_violations(&account));
+        }
+        // with no row applied there is nothing the ledger should add up to
+        if let Some(expected) = self.expected {
+            if actual != expected {
+                found.push(Invariant::LedgerTotal { actual, expected });
+            }
+        }
+        self.record(None, found)
+    }
+
+    fn record(&mut self, row: Option<u64>, found: Vec<Invariant>) -> Result<()> {
+        for invariant in found {
+            let violation = Violation { row, invariant };
+            if self.check == InvariantCheck::Strict {
+                return Err(Error::Invariant(violation));
+            }
+            self.violations.push(violation);
+        }
+        Ok(())
+    }
+}
+
+// what a row moved in or out of the ledger as a whole. `record` is the deposit or withdrawal
+// the row created or referenced, `None` when nothing was applied
+fn moved_by(event: TxType, record: Option<&Transaction>) -> Result<Amount> {
+    let Some(record) = record else {
+        return Ok(Amount::ZERO);
+    };
+    let amount = record.amount;
+    let moved = match (event, record.tx_type) {
+        (TxType::Deposit, _) => amount,
+        (TxType::Withdrawal, _) => Amount::ZERO.checked_sub(amount)?,
+        // a deposit charged back leaves the ledger
+        (TxType::Chargeback, TxType::Deposit) => Amount::ZERO.checked_sub(amount)?,
+        // a disputed withdrawal is credited back provisionally, and taken back if resolved
+        (TxType::Dispute, TxType::Withdrawal) => amount,
+        (TxType::Resolve, TxType::Withdrawal) => Amount::ZERO.checked_sub(amount)?,
+        _ => Amount::ZERO,
+    };
+    Ok(moved)
+}
+
+fn account_violations(acc: &Account) -> Vec<Invariant> {
+    let mut found = Vec::new();
+    if acc.available.checked_add(acc.held).ok() != Some(acc.total) {
+        found.push(Invariant::Balance {
+            client: acc.id,
+            available: acc.available,
+            held: acc.held,
+            total: acc.total,
+        });
+    }
+    if acc.held < Amount::ZERO {
+        found.push(Invariant::NegativeHeld {
+            client: acc.id,
+            held: acc.held,
+        });
+    }
+    found
+}
+
+impl fmt::Display for Invariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Invariant::Balance {
+                client,
+                available,
+                held,
+                total,
+            } => write!(
+                f,
+                "client {} has total {}, but available {} and held {}",
+                client, total, available, held
+            ),
+            Invariant::NegativeHeld { client, held } => {
+                write!(f, "client {} holds a negative amount ({})", client, held)
+            }
+            Invariant::LedgerTotal { actual, expected } => write!(
+                f,
+                "accounts add up to {}, but deposits, withdrawals and chargebacks to {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.row {
+            Some(row) => write!(f, "{} at row {}", self.invariant, row),
+            None => write!(f, "{}", self.invariant),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::amt;
+    use crate::transaction::TxState;
+
+    fn account(client: u16, available: &str, held: &str, total: &str) -> Account {
+        Account {
+            id: client,
+            available: amt(available),
+            held: amt(held),
+            total: amt(total),
+            locked: false,
+        }
+    }
+
+    fn record(tx_type: TxType, amount: &str) -> Transaction {
+        Transaction {
+            tx_type,
+            client: 1,
+            tx: "1".to_string(),
+            amount: amt(amount),
+            state: TxState::Processed,
+        }
+    }
+
+    #[test]
+    fn test_rows_that_keep_the_invariants() {
+        let mut invariants = Invariants::new(InvariantCheck::Report);
+        let deposit = record(TxType::Deposit, "10.0");
+        let steps = [
+            (
+                TxType::Deposit,
+                account(1, "0", "0", "0"),
+                account(1, "10", "0", "10"),
+            ),
+            (
+                TxType::Dispute,
+                account(1, "10", "0", "10"),
+                account(1, "0", "10", "10"),
+            ),
+            (
+                TxType::Chargeback,
+                account(1, "0", "10", "10"),
+                account(1, "0", "0", "0"),
+            ),
+        ];
+        for (row, (event, before, after)) in steps.iter().enumerate() {
+            let opening = || Ok(amt("5.0"));
+            invariants
+                .check_row(
+                    Some(row as u64 + 1),
+                    *event,
+                    Some(&deposit),
+                    before,
+                    after,
+                    opening,
+                )
+                .unwrap();
+        }
+        let accounts = vec![Ok(account(1, "0", "0", "0")), Ok(account(2, "5", "0", "5"))];
+        invariants.check_ledger(accounts).unwrap();
+
+        assert_eq!(invariants.violations(), &[]);
+    }
+
+    #[test]
+    fn test_violations_are_reported_with_their_row() {
+        let mut invariants = Invariants::new(InvariantCheck::Report);
+        let deposit = record(TxType::Deposit, "10.0");
+        let before = account(1, "0", "0", "0");
+        // the deposit went in twice, and held went negative
+        let after = account(1, "21", "-1", "20");
+        invariants
+            .check_row(
+                Some(3),
+                TxType::Deposit,
+                Some(&deposit),
+                &before,
+                &after,
+                || Ok(Amount::ZERO),
+            )
+            .unwrap();
+        invariants.check_ledger(vec![Ok(after)]).unwrap();
+
+        let found: Vec<(Option<u64>, &Invariant)> = invariants
+            .violations()
+            .iter()
+            .map(|violation| (violation.row, &violation.invariant))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    Some(3),
+                    &Invariant::NegativeHeld {
+                        client: 1,
+                        held: amt("-1")
+                    }
+                ),
+                (
+                    Some(3),
+                    &Invariant::LedgerTotal {
+                        actual: amt("20"),
+                        expected: amt("10")
+                    }
+                ),
+                (
+                    None,
+                    &Invariant::NegativeHeld {
+                        client: 1,
+                        held: amt("-1")
+                    }
+                ),
+                (
+                    None,
+                    &Invariant::LedgerTotal {
+                        actual: amt("20"),
+                        expected: amt("10")
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            invariants.violations()[0].to_string(),
+            "client 1 holds a negative amount (-1.0000) at row 3"
+        );
+    }
+
+    #[test]
+    fn test_strict_stops_at_the_first_violation() {
+        let mut invariants = Invariants::new(InvariantCheck::Strict);
+        let before = account(1, "0", "0", "0");
+        let after = account(1, "10", "0", "9");
+        let deposit = record(TxType::Deposit, "9.0");
+        let result = invariants.check_row(
+            Some(7),
+            TxType::Deposit,
+            Some(&deposit),
+            &before,
+            &after,
+            || Ok(Amount::ZERO),
+        );
+
+        match result {
+            Err(Error::Invariant(violation)) => {
+                assert_eq!(violation.row, Some(7));
+                assert!(matches!(violation.invariant, Invariant::Balance { .. }));
+            }
+            other => panic!("expected an invariant violation, got {:?}", other),
+        }
+        assert!(invariants.violations().is_empty());
+    }
+
+    #[test]
+    fn test_withdrawal_disputes_move_the_ledger_total() {
+        let withdrawal = record(TxType::Withdrawal, "4.0");
+        assert_eq!(
+            moved_by(TxType::Withdrawal, Some(&withdrawal)).unwrap(),
+            amt("-4")
+        );
+        assert_eq!(
+            moved_by(TxType::Dispute, Some(&withdrawal)).unwrap(),
+            amt("4")
+        );
+        assert_eq!(
+            moved_by(TxType::Resolve, Some(&withdrawal)).unwrap(),
+            amt("-4")
+        );
+        assert_eq!(
+            moved_by(TxType::Chargeback, Some(&withdrawal)).unwrap(),
+            Amount::ZERO
+        );
+        assert_eq!(moved_by(TxType::Deposit, None).unwrap(), Amount::ZERO);
+    }
+}
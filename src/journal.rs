@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+
+use crate::account::Account;
+use crate::amount::Amount;
+use crate::transaction::TxType;
+
+// One transaction the engine applied or rejected, with the balances of its account on both
+// sides. The journal is only ever appended to, so it explains how every balance came to be.
+//
+// Rows that don't parse never reach the engine and are not journaled: they only show up in
+// the rejected rows report.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    // assigned by the storage when the entry is appended, starting at 1
+    pub seq: u64,
+    // the input row the transaction came from. `None` when it was applied on its own, such as
+    // over the TCP or HTTP servers
+    pub row: Option<u64>,
+    // the input the row was read from, see `Engine::set_source`. Entries journaled before it
+    // was recorded have none
+    #[serde(default)]
+    pub source: Option<String>,
+    pub tx: String,
+    pub client: u16,
+    #[serde(rename = "type")]
+    pub event: TxType,
+    // what the row moved: for disputes, resolves and chargebacks that is the amount of the
+    // referenced transaction, once it is known
+    pub amount: Amount,
+    pub outcome: Outcome,
+    // the reason code of a rejection
+    pub reason: Option<String>,
+    pub available_before: Amount,
+    pub held_before: Amount,
+    pub total_before: Amount,
+    pub locked_before: bool,
+    pub available_after: Amount,
+    pub held_after: Amount,
+    pub total_after: Amount,
+    pub locked_after: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Applied,
+    // a deposit or withdrawal sent again with the same id, client and amount: nothing changed
+    Ignored,
+    Rejected,
+}
+
+// Which entries to return when reading the journal back
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JournalFilter {
+    pub client: Option<u16>,
+    pub tx: Option<String>,
+}
+
+impl JournalEntry {
+    pub fn new(
+        row: Option<u64>,
+        tx: &str,
+        event: TxType,
+        amount: Amount,
+        before: &Account,
+        after: &Account,
+    ) -> JournalEntry {
+        JournalEntry {
+            seq: 0,
+            row,
+            source: None,
+            tx: tx.to_string(),
+            client: after.id,
+            event,
+            amount,
+            outcome: Outcome::Applied,
+            reason: None,
+            available_before: before.available,
+            held_before: before.held,
+            total_before: before.total,
+            locked_before: before.locked,
+            available_after: after.available,
+            held_after: after.held,
+            total_after: after.total,
+            locked_after: after.locked,
+        }
+    }
+}
+
+impl JournalFilter {
+    pub fn matches(&self, entry: &JournalEntry) -> bool {
+        self.client.is_none_or(|client| client == entry.client)
+            && self.tx.as_ref().is_none_or(|tx| *tx == entry.tx)
+    }
+}
@@ -0,0 +1,37 @@
+pub mod account;
+pub mod amount;
+pub mod engine;
+pub mod error;
+pub mod generate;
+pub mod http;
+pub mod input;
+pub mod invariants;
+pub mod journal;
+pub mod output;
+pub mod parallel;
+pub mod recovery;
+#[cfg(test)]
+mod reference;
+pub mod report;
+pub mod server;
+pub mod storage;
+#[cfg(test)]
+mod test_util;
+pub mod transaction;
+
+pub use account::Account;
+pub use amount::Amount;
+pub use engine::{Config, Durability, Engine, LockPolicy, WithdrawalDisputePolicy};
+pub use error::{Error, Result, RuleViolation};
+pub use generate::GeneratorConfig;
+pub use http::HttpServer;
+pub use input::{CsvRows, InputFormat, JsonlRows, Row, Rows};
+pub use invariants::{Invariant, InvariantCheck, Violation};
+pub use journal::{JournalEntry, JournalFilter, Outcome};
+pub use output::OutputFormat;
+pub use parallel::{RejectedRow, ShardedEngine, ShardedOutcome};
+pub use recovery::Mismatch;
+pub use report::{Rejection, RejectionWriter, ReportFormat};
+pub use server::Server;
+pub use storage::{Commit, MemoryStorage, SledStorage, Storage};
+pub use transaction::{DisputeEvent, Transaction, TxState, TxType};
@@ -1,313 +1,626 @@
-mod account;
-mod transaction;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use tx_processing::output::write_accounts;
+use tx_processing::recovery;
+use tx_processing::{
+    Config, Durability, Engine, GeneratorConfig, HttpServer, InputFormat, InvariantCheck,
+    JournalFilter, LockPolicy, MemoryStorage, Mismatch, OutputFormat, Rejection, RejectionWriter,
+    ReportFormat, Result, Rows, Server, ShardedEngine, SledStorage, Storage, Violation,
+    WithdrawalDisputePolicy,
+};
 
-use std::error::Error;
-use std::fs::{remove_dir_all, File};
-use std::io::BufReader;
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Delete the persistent ledger kept in a --db-path directory
+    Reset {
+        #[arg(long)]
+        db_path: PathBuf,
+    },
+    /// Check that every account in a --db-path ledger matches its transaction records
+    Check {
+        #[arg(long)]
+        db_path: PathBuf,
+    },
+    /// Rebuild every account of a --db-path ledger from its journal and report the clients
+    /// whose stored account differs
+    Rebuild {
+        #[arg(long)]
+        db_path: PathBuf,
+    },
+    /// Print the disputes, resolves and chargebacks applied to a transaction, oldest first
+    History {
+        #[arg(long)]
+        db_path: PathBuf,
+        /// Id of the disputed transaction
+        tx: String,
+    },
+    /// Print the journal of every transaction applied to or rejected by a --db-path ledger
+    Journal {
+        #[arg(long)]
+        db_path: PathBuf,
+        /// Only entries of this client
+        #[arg(long)]
+        client: Option<u16>,
+        /// Only entries of this transaction id, including its disputes
+        #[arg(long)]
+        tx: Option<String>,
+    },
+    /// Accept newline-delimited CSV rows over TCP and apply them to a shared ledger
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:7878")]
+        listen: String,
+        #[command(flatten)]
+        ledger: LedgerArgs,
+    },
+    /// Serve a JSON API to post transactions and query accounts and transactions
+    Http {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        #[command(flatten)]
+        ledger: LedgerArgs,
+    },
+    /// Write random transactions to use as input, the same ones for the same options and seed
+    Generate(GenerateArgs),
+}
 
-use clap::Parser;
-use csv::{ReaderBuilder, Trim, Writer};
-use serde_json::{from_slice, to_string};
-use sled::Db;
-use transaction::TxType;
+// what a long-running ledger is served over
+#[derive(Clone, Copy)]
+enum Protocol {
+    Tcp,
+    Http,
+}
 
-use crate::account::Account;
-use crate::transaction::Transaction;
+#[derive(Args)]
+struct ProcessArgs {
+    /// File to read, or `-` for stdin [default: stdin]
+    filepath: Option<String>,
+    /// Format of the input [default: jsonl for *.jsonl and *.ndjson files, csv otherwise]
+    #[arg(long, value_enum)]
+    input_format: Option<InputFormatArg>,
+    #[command(flatten)]
+    ledger: LedgerArgs,
+    /// Number of worker threads; rows are split between them by client id
+    #[arg(long, default_value_t = 1, conflicts_with = "db_path")]
+    workers: usize,
+    /// Write every row that was not applied, with its reason code, to this file
+    #[arg(long)]
+    rejected: Option<PathBuf>,
+    /// Format of the --rejected file [default: jsonl for *.jsonl paths, csv otherwise]
+    #[arg(long, value_enum, requires = "rejected")]
+    rejected_format: Option<RejectedFormat>,
+    /// Write the account summary to this file instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Format of the account summary [default: from the --output extension, csv otherwise]
+    #[arg(long, value_enum)]
+    output_format: Option<OutputFormatArg>,
+    /// Check the ledger invariants after every applied row and at the end of the run, printing
+    /// every violation to stderr and carrying on
+    #[arg(long)]
+    check_invariants: bool,
+    /// Check the ledger invariants like --check-invariants, but stop at the first violation,
+    /// before the row that caused it is committed
+    #[arg(long)]
+    strict_invariants: bool,
+}
 
-#[derive(Parser)]
-struct Cli {
-    filepath: String,
+#[derive(Args)]
+struct GenerateArgs {
+    /// Number of clients, with ids from 1
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u16).range(1..))]
+    clients: u16,
+    /// Number of rows, not counting the header
+    #[arg(long, default_value_t = 1000)]
+    rows: u64,
+    /// Share of rows that dispute an earlier deposit
+    #[arg(long, default_value_t = 0.02, value_parser = rate)]
+    dispute_rate: f64,
+    /// Share of disputes that are later resolved
+    #[arg(long, default_value_t = 0.6, value_parser = rate)]
+    resolve_rate: f64,
+    /// Share of disputes that are later charged back
+    #[arg(long, default_value_t = 0.2, value_parser = rate)]
+    chargeback_rate: f64,
+    /// Share of rows that don't parse
+    #[arg(long, default_value_t = 0.0, value_parser = rate)]
+    invalid_rate: f64,
+    /// Seed of the random rows [default: a random one, printed to stderr]
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Write the rows to this file instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+// Where a processing run reads from and writes to
+struct Streams {
+    input: Box<dyn Read>,
+    // what the journal says the rows came from
+    source: String,
+    input_format: InputFormat,
+    report: Option<RejectionWriter<BufWriter<File>>>,
+    output: Box<dyn Write>,
+    output_format: OutputFormat,
+}
+
+// Where the ledger lives and the rules it is kept by
+#[derive(Args)]
+struct LedgerArgs {
+    /// Keep accounts and transactions in memory instead of on disk
+    #[arg(long)]
+    in_memory: bool,
+    /// Directory of a persistent ledger, kept between runs so each file builds on the previous ones
+    #[arg(long, conflicts_with = "in_memory")]
+    db_path: Option<PathBuf>,
+    /// Directory in which a run without --db-path creates its own temporary database [default: the system temp dir]
+    #[arg(long, conflicts_with_all = ["in_memory", "db_path"])]
+    data_dir: Option<PathBuf>,
+    /// Keep accepting disputes, resolves and chargebacks on accounts locked by a chargeback
+    #[arg(long)]
+    allow_disputes_on_locked: bool,
+    /// When applied rows are flushed to disk
+    #[arg(long, value_enum, default_value = "row")]
+    durability: DurabilityMode,
+    /// Rows per flush with --durability batch
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    batch_size: u64,
+    /// How disputes of withdrawals are handled
+    #[arg(long, value_enum, default_value = "reject")]
+    withdrawal_disputes: WithdrawalDisputes,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DurabilityMode {
+    /// Flush after every row: nothing is lost on a crash
+    Row,
+    /// Flush every --batch-size rows: a crash loses at most the last batch
+    Batch,
+    /// Flush once at the end: fastest, a crash can lose the whole run
+    End,
 }
 
-fn main() {
+#[derive(Clone, Copy, ValueEnum)]
+enum WithdrawalDisputes {
+    /// Reject them, only deposits can be disputed
+    Reject,
+    /// Credit the withdrawn amount back as held funds until the dispute settles
+    ProvisionalCredit,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum InputFormatArg {
+    /// A header line, then one row per line
+    Csv,
+    /// One JSON object per line, with the fields of a CSV row
+    Jsonl,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormatArg {
+    /// client,available,held,total,locked
+    Csv,
+    /// One JSON array of accounts
+    Json,
+    /// One JSON account per line
+    Jsonl,
+    /// Aligned columns, for reading
+    Table,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RejectedFormat {
+    Csv,
+    Jsonl,
+}
+
+fn main() -> ExitCode {
     let cli = Cli::parse();
-    let filepath = cli.filepath;
 
-    if let Err(e) = process_transactions(filepath) {
-        eprintln!("Error processing transactions: {}", e);
-    }
+    let (result, doing) = match cli.command {
+        Some(Command::Reset { db_path }) => (
+            SledStorage::remove(&db_path).map(|()| ExitCode::SUCCESS),
+            "resetting ledger",
+        ),
+        Some(Command::Check { db_path }) => (check(&db_path), "checking ledger"),
+        Some(Command::Rebuild { db_path }) => (rebuild(&db_path), "rebuilding ledger"),
+        Some(Command::History { db_path, tx }) => (
+            history(&db_path, &tx).map(|()| ExitCode::SUCCESS),
+            "reading history",
+        ),
+        Some(Command::Journal {
+            db_path,
+            client,
+            tx,
+        }) => (
+            journal(&db_path, JournalFilter { client, tx }).map(|()| ExitCode::SUCCESS),
+            "reading journal",
+        ),
+        Some(Command::Serve { listen, ledger }) => (
+            serve(&listen, ledger, Protocol::Tcp).map(|()| ExitCode::SUCCESS),
+            "serving transactions",
+        ),
+        Some(Command::Http { listen, ledger }) => (
+            serve(&listen, ledger, Protocol::Http).map(|()| ExitCode::SUCCESS),
+            "serving transactions",
+        ),
+        Some(Command::Generate(args)) => (
+            generate(args).map(|()| ExitCode::SUCCESS),
+            "generating transactions",
+        ),
+        None => (
+            run(cli.process).map(|()| ExitCode::SUCCESS),
+            "processing transactions",
+        ),
+    };
+
+    // any error, and a ledger that doesn't add up, fail the process so scripts can tell
+    result.unwrap_or_else(|e| {
+        eprintln!("Error {}: {}", doing, e);
+        ExitCode::FAILURE
+    })
 }
 
-fn process_transactions(filename: String) -> Result<(), Box<dyn Error>> {
-    // two different K/V databases, to hold Accounts and Transactions on disk instead of in memory,
-    // in a somewhat "hashmap" fashion
-    let tx_db = sled::open(Transaction::DB_NAME)?;
-    let ac_db = sled::open(Account::DB_NAME)?;
-
-    let file = File::open(&filename).map_err(|_| "Error opening CSV file")?;
-    // Use buffreader so the file is not loaded in memory all at once
-    let filereader = BufReader::new(file);
-    let mut csv_reader = ReaderBuilder::new()
-        .trim(Trim::All)
-        .has_headers(true)
-        .from_reader(filereader);
-
-    for result in csv_reader.deserialize::<Transaction>() {
-        let mut tx: Transaction = result?;
-        let mut acc = get_or_create_account(&ac_db, tx.client)?;
-
-        match process_transaction(&tx_db, &mut acc, &mut tx) {
-            Ok(()) => {
-                insert_account(&ac_db, &acc)?;
-            }
-            Err(e) => eprintln!("Error processing transaction: {}", e),
+fn run(args: ProcessArgs) -> Result<()> {
+    // opened before the output files are created, so a wrong input path doesn't wipe the
+    // summary and report of the previous run
+    let input = open_input(args.filepath.as_deref())?;
+    let report = match &args.rejected {
+        Some(path) => Some(RejectionWriter::new(
+            BufWriter::new(File::create(path)?),
+            rejected_format(path, args.rejected_format),
+        )),
+        None => None,
+    };
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    let streams = Streams {
+        input,
+        source: match args.filepath.as_deref() {
+            None | Some("-") => "stdin".to_string(),
+            Some(path) => path.to_string(),
+        },
+        input_format: input_format(args.filepath.as_deref(), args.input_format),
+        report,
+        output,
+        output_format: output_format(args.output.as_deref(), args.output_format),
+    };
+
+    let ledger = args.ledger;
+    let config = Config {
+        invariants: match (args.check_invariants, args.strict_invariants) {
+            (_, true) => InvariantCheck::Strict,
+            (true, false) => InvariantCheck::Report,
+            (false, false) => InvariantCheck::Off,
+        },
+        ..ledger.config()
+    };
+    let data_dir = ledger.data_dir();
+
+    if args.workers > 1 {
+        if ledger.in_memory {
+            let accounts = || Ok(MemoryStorage::new());
+            let engine = ShardedEngine::new(args.workers, config, accounts, MemoryStorage::new())?;
+            return process_sharded(engine, streams);
         }
+        let transactions = SledStorage::temporary_in(&data_dir)?;
+        let accounts = || SledStorage::temporary_in(&data_dir);
+        let engine = ShardedEngine::new(args.workers, config, accounts, transactions)?;
+        return process_sharded(engine, streams);
     }
 
-    output_db_as_csv(&ac_db)?;
-    cleanup();
-    Ok(())
+    if ledger.in_memory {
+        let engine = Engine::with_config(MemoryStorage::new(), config);
+        return process_transactions(engine, streams);
+    }
+    if let Some(db_path) = &ledger.db_path {
+        let engine = Engine::with_config(open_ledger(db_path)?, config);
+        return process_transactions(engine, streams);
+    }
+
+    let engine = Engine::with_config(SledStorage::temporary_in(&data_dir)?, config);
+    process_transactions(engine, streams)
 }
 
-fn process_transaction(
-    tx_db: &Db,
-    acc: &mut Account,
-    tx: &mut Transaction,
-) -> Result<(), Box<dyn Error>> {
-    match get_transaction(tx_db, &tx.tx)? {
-        Some(mut updated_tx) => {
-            if tx.tx_type == updated_tx.tx_type && tx.amount == updated_tx.amount {
-                return Ok(()); // Idempotent transaction, nothing to do
-            }
-
-            // adding suffix to tx so they don't overwrite Deposits and Withdrawals,
-            // which can be disputed later
-            tx.tx.push_str(match tx.tx_type {
-                TxType::Dispute => "-d",
-                TxType::Resolve => "-r",
-                TxType::Chargeback => "-c",
-                _ => "",
-            });
-
-            match tx.tx_type {
-                TxType::Deposit => tx.deposit(acc),
-                TxType::Withdrawal => tx.withdrawal(acc),
-                TxType::Dispute => updated_tx.dispute(acc),
-                TxType::Resolve => updated_tx.resolve(acc),
-                TxType::Chargeback => updated_tx.chargeback(acc),
-            }
-
-            insert_transaction(tx_db, &updated_tx)?;
-        }
-        None => {
-            match tx.tx_type {
-                TxType::Deposit => tx.deposit(acc),
-                TxType::Withdrawal => tx.withdrawal(acc),
-                TxType::Dispute | TxType::Resolve | TxType::Chargeback => return Ok(()),
-            }
-            insert_transaction(tx_db, &tx)?;
-        }
+// a share, between 0 and 1
+fn rate(value: &str) -> std::result::Result<f64, String> {
+    let rate: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(format!("{} is not between 0 and 1", rate));
     }
-    Ok(())
+    Ok(rate)
 }
 
-fn get_or_create_account(db: &Db, client_id: u16) -> Result<Account, Box<dyn Error>> {
-    // for each transaction, one account fetched or created
-    // check if transaction with same tx (id) already stored
-    match get_account(db, client_id)? {
-        Some(acc) => Ok(acc),
-        None => Ok(Account::new(client_id)),
+fn generate(args: GenerateArgs) -> Result<()> {
+    let seed = args.seed.unwrap_or_else(|| {
+        let seed = rand::random();
+        eprintln!("Seed: {}", seed);
+        seed
+    });
+    let config = GeneratorConfig {
+        clients: args.clients,
+        rows: args.rows,
+        dispute_rate: args.dispute_rate,
+        resolve_rate: args.resolve_rate,
+        chargeback_rate: args.chargeback_rate,
+        invalid_rate: args.invalid_rate,
+        seed,
+    };
+    match &args.output {
+        Some(path) => tx_processing::generate::generate(&config, File::create(path)?),
+        None => tx_processing::generate::generate(&config, io::stdout().lock()),
     }
 }
 
-fn insert_account(db: &Db, account: &Account) -> Result<(), Box<dyn Error>> {
-    let serialized_data = to_string(account)?;
-    db.insert(account.id.to_be_bytes(), serialized_data.as_bytes())?;
-    db.flush()?;
-    Ok(())
+fn serve(listen: &str, ledger: LedgerArgs, protocol: Protocol) -> Result<()> {
+    let config = ledger.config();
+    if ledger.in_memory {
+        let engine = Engine::with_config(MemoryStorage::new(), config);
+        return serve_engine(listen, engine, protocol);
+    }
+    if let Some(db_path) = &ledger.db_path {
+        let engine = Engine::with_config(open_ledger(db_path)?, config);
+        return serve_engine(listen, engine, protocol);
+    }
+
+    let engine = Engine::with_config(SledStorage::temporary_in(&ledger.data_dir())?, config);
+    serve_engine(listen, engine, protocol)
 }
 
-fn get_account(db: &Db, key: u16) -> Result<Option<Account>, Box<dyn Error>> {
-    if let Some(serialized_data) = db.get(key.to_be_bytes())? {
-        let account: Account = from_slice(&serialized_data)?;
-        Ok(Some(account))
-    } else {
-        Ok(None)
+fn serve_engine<S>(listen: &str, engine: Engine<S>, protocol: Protocol) -> Result<()>
+where
+    S: Storage + Send + 'static,
+{
+    match protocol {
+        Protocol::Tcp => {
+            let server = Server::bind(listen, engine)?;
+            eprintln!("Listening on {}", server.local_addr()?);
+            server.run()
+        }
+        Protocol::Http => {
+            let server = HttpServer::bind(listen, engine)?;
+            eprintln!("Listening on http://{}", server.local_addr()?);
+            server.run()
+        }
     }
 }
 
-fn insert_transaction(db: &Db, tx: &Transaction) -> Result<(), Box<dyn Error>> {
-    let serialized_data = to_string(tx)?;
-    db.insert(tx.tx.as_bytes(), serialized_data.as_bytes())?;
-    db.flush()?;
-    Ok(())
-}
+impl LedgerArgs {
+    fn config(&self) -> Config {
+        Config {
+            lock_policy: if self.allow_disputes_on_locked {
+                LockPolicy::AllowDisputes
+            } else {
+                LockPolicy::BlockAll
+            },
+            withdrawal_disputes: match self.withdrawal_disputes {
+                WithdrawalDisputes::Reject => WithdrawalDisputePolicy::Reject,
+                WithdrawalDisputes::ProvisionalCredit => WithdrawalDisputePolicy::ProvisionalCredit,
+            },
+            durability: match self.durability {
+                DurabilityMode::Row => Durability::EveryRow,
+                DurabilityMode::Batch => Durability::EveryN(self.batch_size),
+                DurabilityMode::End => Durability::AtEnd,
+            },
+            invariants: InvariantCheck::Off,
+        }
+    }
 
-fn get_transaction(db: &Db, key: &String) -> Result<Option<Transaction>, Box<dyn Error>> {
-    if let Some(serialized_data) = db.get(key.as_bytes())? {
-        let tx: Transaction = from_slice(&serialized_data)?;
-        Ok(Some(tx))
-    } else {
-        Ok(None)
+    // without --db-path the ledger only lives for this run, in a directory no other run uses
+    fn data_dir(&self) -> PathBuf {
+        self.data_dir.clone().unwrap_or_else(std::env::temp_dir)
     }
 }
 
-fn output_db_as_csv(db: &Db) -> Result<(), Box<dyn Error>> {
-    let mut wtr = Writer::from_writer(std::io::stdout());
+fn open_ledger(db_path: &Path) -> Result<SledStorage> {
+    let storage = SledStorage::open(db_path)?;
+    if storage.migrated_legacy() {
+        report_mismatches(&storage)?;
+    }
+    Ok(storage)
+}
 
-    wtr.write_record(&["client", "available", "held", "total", "locked"])?;
+fn check(db_path: &Path) -> Result<ExitCode> {
+    let storage = SledStorage::open(db_path)?;
+    if report_mismatches(&storage)? > 0 {
+        return Ok(ExitCode::FAILURE);
+    }
+    println!("Ledger is consistent");
+    Ok(ExitCode::SUCCESS)
+}
 
-    for result in db.iter() {
-        let (_, value) = result?;
-        let account: Account = from_slice(&value)?;
-        wtr.serialize((
-            account.id,
-            format!("{:.4}", account.available),
-            format!("{:.4}", account.held),
-            format!("{:.4}", account.total),
-            account.locked,
-        ))?;
+fn rebuild(db_path: &Path) -> Result<ExitCode> {
+    let storage = SledStorage::open(db_path)?;
+    let mismatches = recovery::rebuild(&storage)?;
+    print_mismatches(&mismatches, "the journal adds up to");
+    if !mismatches.is_empty() {
+        return Ok(ExitCode::FAILURE);
     }
+    println!("Ledger matches its journal");
+    Ok(ExitCode::SUCCESS)
+}
 
+fn history(db_path: &Path, tx: &str) -> Result<()> {
+    let storage = SledStorage::open(db_path)?;
+    let mut wtr = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(io::stdout().lock());
+    wtr.write_record(["tx", "seq", "type", "client", "amount", "state"])?;
+    for event in storage.events(tx)? {
+        wtr.serialize(event)?;
+    }
     wtr.flush()?;
     Ok(())
 }
 
-fn cleanup() {
-    let _ = remove_dir_all(Account::DB_NAME);
-    let _ = remove_dir_all(Transaction::DB_NAME);
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use csv::ReaderBuilder;
-    use sled::Config;
-    use std::io::Cursor;
-
-    #[test]
-    fn test_process_transactions_in_memory() {
-        // Sample CSV data in memory
-        let csv_data = "\
-            type,client,tx,amount\n\
-            deposit,1,tx1,100.0\n\
-            withdrawal,1,tx2,50.0\n";
-
-        let tx_db = Config::new().temporary(true).open().unwrap();
-        let ac_db = Config::new().temporary(true).open().unwrap();
-
-        let mut csv_reader = ReaderBuilder::new()
-            .trim(Trim::All)
-            .has_headers(true)
-            .from_reader(Cursor::new(csv_data));
-
-        for result in csv_reader.deserialize::<Transaction>() {
-            let mut tx: Transaction = result.unwrap();
-            let mut acc = get_or_create_account(&ac_db, tx.client).unwrap();
-
-            match process_transaction(&tx_db, &mut acc, &mut tx) {
-                Ok(()) => {
-                    insert_account(&ac_db, &acc).unwrap();
-                }
-                Err(e) => eprintln!("Error processing transaction: {}", e),
-            }
-        }
-
-        // Check if account data is updated correctly
-        let account = get_account(&ac_db, 1).unwrap().unwrap();
-        assert_eq!(account.available, 50.0);
-        assert_eq!(account.total, 50.0);
-        assert_eq!(account.held, 0.0);
-    }
-
-    #[test]
-    fn test_get_or_create_account_in_memory() {
-        let db = Config::new().temporary(true).open().unwrap();
-
-        // Creating a new account
-        let account = get_or_create_account(&db, 1).unwrap();
-        assert_eq!(account.id, 1);
-        assert_eq!(account.total, 0.0);
-        assert_eq!(account.available, 0.0);
-        assert_eq!(account.held, 0.0);
-        assert!(!account.locked);
-
-        // Fetching an existing account
-        insert_account(&db, &account).unwrap();
-        let fetched_account = get_or_create_account(&db, 1).unwrap();
-        assert_eq!(fetched_account.id, 1);
+fn journal(db_path: &Path, filter: JournalFilter) -> Result<()> {
+    let engine = Engine::new(SledStorage::open(db_path)?);
+    let mut wtr = csv::Writer::from_writer(io::stdout().lock());
+    for entry in engine.journal(&filter) {
+        wtr.serialize(entry?)?;
     }
+    wtr.flush()?;
+    Ok(())
+}
 
-    #[test]
-    fn test_insert_and_get_account_in_memory() {
-        let db = Config::new().temporary(true).open().unwrap();
+// clients whose balances don't match their transaction records, most likely torn by a crash
+// in a version that wrote accounts and transactions separately
+fn report_mismatches<S: Storage>(storage: &S) -> Result<usize> {
+    let mismatches = recovery::check(storage)?;
+    print_mismatches(&mismatches, "transactions add up to");
+    Ok(mismatches.len())
+}
 
-        let account = Account {
-            id: 1,
-            total: 100.0,
-            available: 100.0,
-            held: 0.0,
-            locked: false,
+fn print_mismatches(mismatches: &[Mismatch], source: &str) {
+    for mismatch in mismatches {
+        let stored = match &mismatch.stored {
+            Some(acc) => format!(
+                "available {}, held {}, total {}, locked {}",
+                acc.available, acc.held, acc.total, acc.locked
+            ),
+            None => "no account".to_string(),
         };
+        let expected = &mismatch.expected;
+        eprintln!(
+            "Client {}: stored {}; {} available {}, held {}, total {}, locked {}",
+            mismatch.client,
+            stored,
+            source,
+            expected.available,
+            expected.held,
+            expected.total,
+            expected.locked
+        );
+    }
+}
 
-        insert_account(&db, &account).unwrap();
-        let fetched_account = get_account(&db, 1).unwrap().unwrap();
-
-        assert_eq!(fetched_account.id, 1);
-        assert_eq!(fetched_account.total, 100.0);
-        assert_eq!(fetched_account.available, 100.0);
-        assert_eq!(fetched_account.held, 0.0);
-        assert!(!fetched_account.locked);
+fn report_violations(violations: &[Violation]) {
+    for violation in violations {
+        eprintln!("Invariant violated: {}", violation);
     }
+}
 
-    #[test]
-    fn test_insert_and_get_transaction_in_memory() {
-        let db = Config::new().temporary(true).open().unwrap();
+fn open_input(filepath: Option<&str>) -> Result<Box<dyn Read>> {
+    match filepath {
+        None | Some("-") => Ok(Box::new(io::stdin().lock())),
+        // Use buffreader so the file is not loaded in memory all at once
+        Some(path) => Ok(Box::new(BufReader::new(File::open(path)?))),
+    }
+}
 
-        let transaction = Transaction {
-            tx_type: TxType::Deposit,
-            client: 1,
-            tx: "tx1".to_string(),
-            amount: 100.0,
-            under_dispute: false,
-        };
+fn input_format(filepath: Option<&str>, format: Option<InputFormatArg>) -> InputFormat {
+    let extension = filepath.and_then(|path| Path::new(path).extension());
+    match format {
+        Some(InputFormatArg::Csv) => InputFormat::Csv,
+        Some(InputFormatArg::Jsonl) => InputFormat::Jsonl,
+        None if extension.is_some_and(|ext| ext == "jsonl" || ext == "ndjson") => {
+            InputFormat::Jsonl
+        }
+        None => InputFormat::Csv,
+    }
+}
 
-        insert_transaction(&db, &transaction).unwrap();
-        let fetched_transaction = get_transaction(&db, &"tx1".to_string()).unwrap().unwrap();
+fn output_format(path: Option<&Path>, format: Option<OutputFormatArg>) -> OutputFormat {
+    let format = format.or_else(|| match path?.extension()?.to_str()? {
+        "json" => Some(OutputFormatArg::Json),
+        "jsonl" | "ndjson" => Some(OutputFormatArg::Jsonl),
+        "txt" => Some(OutputFormatArg::Table),
+        _ => None,
+    });
+    match format {
+        Some(OutputFormatArg::Csv) | None => OutputFormat::Csv,
+        Some(OutputFormatArg::Json) => OutputFormat::Json,
+        Some(OutputFormatArg::Jsonl) => OutputFormat::Jsonl,
+        Some(OutputFormatArg::Table) => OutputFormat::Table,
+    }
+}
 
-        assert_eq!(fetched_transaction.tx_type, TxType::Deposit);
-        assert_eq!(fetched_transaction.client, 1);
-        assert_eq!(fetched_transaction.tx, "tx1");
-        assert_eq!(fetched_transaction.amount, 100.0);
-        assert!(!fetched_transaction.under_dispute);
+fn rejected_format(path: &Path, format: Option<RejectedFormat>) -> ReportFormat {
+    match format {
+        Some(RejectedFormat::Csv) => ReportFormat::Csv,
+        Some(RejectedFormat::Jsonl) => ReportFormat::Jsonl,
+        None if path.extension().is_some_and(|ext| ext == "jsonl") => ReportFormat::Jsonl,
+        None => ReportFormat::Csv,
     }
+}
 
-    #[test]
-    fn test_output_db_as_csv_in_memory() {
-        let db = Config::new().temporary(true).open().unwrap();
+// a malformed or rejected row only affects itself, the rest of the input goes on
+fn reject(
+    report: &mut Option<RejectionWriter<BufWriter<File>>>,
+    rejection: Rejection,
+) -> Result<()> {
+    eprintln!("Row {}: {}", rejection.row, rejection.message);
+    if let Some(report) = report {
+        report.write(&rejection)?;
+    }
+    Ok(())
+}
 
-        let account = Account {
-            id: 1,
-            total: 100.0,
-            available: 100.0,
-            held: 0.0,
-            locked: false,
-        };
+fn process_transactions<S: Storage>(mut engine: Engine<S>, streams: Streams) -> Result<()> {
+    let mut report = streams.report;
+    engine.set_source(streams.source);
+    engine.process(streams.input, streams.input_format, |rejection| {
+        reject(&mut report, rejection)
+    })?;
+    report_violations(engine.violations());
+    if let Some(report) = &mut report {
+        report.flush()?;
+    }
+    engine.write_accounts(streams.output, streams.output_format)
+}
 
-        insert_account(&db, &account).unwrap();
-
-        // Redirect output to a buffer
-        let mut buffer = Vec::new();
-        {
-            let mut writer = csv::Writer::from_writer(&mut buffer);
-            writer
-                .write_record(&["client", "available", "held", "total", "locked"])
-                .unwrap();
-            writer
-                .serialize((
-                    account.id,
-                    format!("{:.4}", account.available),
-                    format!("{:.4}", account.held),
-                    format!("{:.4}", account.total),
-                    account.locked,
-                ))
-                .unwrap();
-            writer.flush().unwrap();
+fn process_sharded<A, T>(mut engine: ShardedEngine<A, T>, streams: Streams) -> Result<()>
+where
+    A: Storage + Send + 'static,
+    T: Storage + Send + 'static,
+{
+    // rows that don't parse never reach the workers; reported along with the others at the end
+    let mut unparsed = Vec::new();
+    let mut report = streams.report;
+    for row in Rows::new(streams.input, streams.input_format)? {
+        match row.parsed {
+            Ok(tx) => engine.submit(row.number, tx)?,
+            Err(e) => unparsed.push((row.number, row.tx, row.client, e)),
         }
+    }
+    let outcome = engine.finish()?;
+    report_violations(&outcome.violations);
+
+    let mut rejected: Vec<_> = outcome
+        .rejected
+        .into_iter()
+        .map(|rejected| {
+            let client = Some(rejected.client);
+            (rejected.row, Some(rejected.tx), client, rejected.error)
+        })
+        .chain(unparsed)
+        .collect();
+    rejected.sort_by_key(|(row, ..)| *row);
+    for (row, tx, client, err) in rejected {
+        let rejection = Rejection::new(row, tx, client, &err).ok_or(err)?;
+        reject(&mut report, rejection)?;
+    }
 
-        let output = String::from_utf8(buffer).unwrap();
-        assert!(output.contains("client,available,held,total,locked"));
-        assert!(output.contains("1,100.0000,0.0000,100.0000,false"));
+    if let Some(report) = &mut report {
+        report.flush()?;
     }
+    let accounts = outcome.accounts.into_iter().map(Ok);
+    write_accounts(accounts, streams.output, streams.output_format)
 }
-
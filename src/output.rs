@@ -0,0 +1,182 @@
+use std::io::Write;
+
+use crate::account::Account;
+use crate::error::Result;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    // `client,available,held,total,locked`, the format the tool always had
+    #[default]
+    Csv,
+    // one array of accounts
+    Json,
+    // one account object per line
+    Jsonl,
+    // aligned columns for people to read
+    Table,
+}
+
+const COLUMNS: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+// Writes the account summary in any format. Amounts are always exact four-decimal strings,
+// JSON included, so nothing on the way out goes through a float.
+pub fn write_accounts<W, I>(accounts: I, mut writer: W, format: OutputFormat) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = Result<Account>>,
+{
+    match format {
+        OutputFormat::Csv => return write_accounts_csv(accounts, writer),
+        OutputFormat::Json => {
+            // streamed one account at a time rather than built as a whole in memory
+            writer.write_all(b"[")?;
+            for (index, account) in accounts.into_iter().enumerate() {
+                writer.write_all(if index == 0 { b"\n  " } else { b",\n  " })?;
+                serde_json::to_writer(&mut writer, &account?)?;
+            }
+            writer.write_all(b"\n]\n")?;
+        }
+        OutputFormat::Jsonl => {
+            for account in accounts {
+                serde_json::to_writer(&mut writer, &account?)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        OutputFormat::Table => write_table(accounts, &mut writer)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn write_accounts_csv<W, I>(accounts: I, writer: W) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = Result<Account>>,
+{
+    let mut wtr = csv::Writer::from_writer(writer);
+
+    wtr.write_record(COLUMNS)?;
+
+    for account in accounts {
+        let account = account?;
+        // amounts serialize as exact four-decimal strings
+        wtr.serialize((
+            account.id,
+            account.available,
+            account.held,
+            account.total,
+            account.locked,
+        ))?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+// columns are as wide as their widest value, so every account has to be read first
+fn write_table<W, I>(accounts: I, writer: &mut W) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = Result<Account>>,
+{
+    let mut rows = Vec::new();
+    for account in accounts {
+        let acc = account?;
+        rows.push([
+            acc.id.to_string(),
+            acc.available.to_string(),
+            acc.held.to_string(),
+            acc.total.to_string(),
+            acc.locked.to_string(),
+        ]);
+    }
+
+    let mut widths = COLUMNS.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let header = COLUMNS.map(str::to_string);
+    let rule = widths.map(|width| "-".repeat(width));
+    for row in [&header, &rule].into_iter().chain(&rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:>width$}", cell, width = width))
+            .collect();
+        writeln!(writer, "{}", cells.join("  "))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounts() -> Vec<Result<Account>> {
+        let mut first = Account::new(1);
+        first.available = "1234.5".parse().unwrap();
+        first.total = first.available;
+        let mut second = Account::new(22);
+        second.held = "0.0001".parse().unwrap();
+        second.total = second.held;
+        second.locked = true;
+        vec![Ok(first), Ok(second)]
+    }
+
+    fn written(format: OutputFormat) -> String {
+        let mut buffer = Vec::new();
+        write_accounts(accounts(), &mut buffer, format).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(
+            written(OutputFormat::Csv),
+            "client,available,held,total,locked\n\
+             1,1234.5000,0.0000,1234.5000,false\n\
+             22,0.0000,0.0001,0.0001,true\n"
+        );
+    }
+
+    #[test]
+    fn test_json_and_jsonl() {
+        let json: serde_json::Value = serde_json::from_str(&written(OutputFormat::Json)).unwrap();
+        assert_eq!(json[0]["client"], 1);
+        assert_eq!(json[0]["available"], "1234.5000");
+        assert_eq!(json[1]["locked"], true);
+
+        let jsonl = written(OutputFormat::Jsonl);
+        let lines: Vec<&str> = jsonl.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"client":1,"available":"1234.5000","held":"0.0000","total":"1234.5000","locked":false}"#,
+                r#"{"client":22,"available":"0.0000","held":"0.0001","total":"0.0001","locked":true}"#,
+            ]
+        );
+
+        let mut empty = Vec::new();
+        write_accounts(Vec::new(), &mut empty, OutputFormat::Json).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Vec<Account>>(&empty).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        assert_eq!(
+            written(OutputFormat::Table),
+            "\
+client  available    held      total  locked
+------  ---------  ------  ---------  ------
+     1  1234.5000  0.0000  1234.5000   false
+    22     0.0000  0.0001     0.0001    true
+"
+        );
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::panic;
+use std::sync::mpsc::{self, Sender, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use crate::account::Account;
+use crate::engine::{Config, Engine};
+use crate::error::{Error, Result};
+use crate::invariants::Violation;
+use crate::journal::JournalEntry;
+use crate::storage::{AccountIter, JournalIter, Storage, TransactionIter};
+use crate::transaction::{DisputeEvent, Transaction};
+
+// rows queued per worker before the dispatcher has to wait for it
+const QUEUE_SIZE: usize = 1024;
+
+// Spreads transactions over worker threads by client id. Each worker owns the accounts of its
+// clients, so rows of one client are applied in input order by a single thread.
+//
+// Transaction ids are global though: a dispute can name another client's deposit, and ids can
+// be reused across clients. Transactions therefore live in one shared store, and the first time
+// a tx id shows up for a second client, every worker is drained before that row goes out. Any
+// row that reads a transaction then sees exactly what a sequential run would have shown it, and
+// the final state matches the sequential one.
+pub struct ShardedEngine<A: Storage, T: Storage> {
+    workers: Vec<Worker<A, T>>,
+    mentions: HashMap<String, Mention>,
+    fatal: Arc<Mutex<Option<Error>>>,
+}
+
+// A row the engine did not apply
+#[derive(Debug)]
+pub struct RejectedRow {
+    pub row: u64,
+    pub tx: String,
+    pub client: u16,
+    pub error: Error,
+}
+
+pub struct ShardedOutcome {
+    // in client id order, like `Engine::accounts`
+    pub accounts: Vec<Account>,
+    // in row order
+    pub rejected: Vec<RejectedRow>,
+    // what the invariant checks of every worker found, rows first and in row order
+    pub violations: Vec<Violation>,
+}
+
+struct Worker<A: Storage, T: Storage> {
+    jobs: SyncSender<Job>,
+    handle: JoinHandle<WorkerResult<A, T>>,
+}
+
+type WorkerResult<A, T> = (Engine<ShardStorage<A, T>>, Vec<RejectedRow>);
+
+enum Job {
+    Apply(u64, Transaction),
+    // acknowledged once every job queued before it has been applied
+    Sync(Sender<()>),
+}
+
+// which clients have used a tx id so far
+enum Mention {
+    One(u16),
+    Many,
+}
+
+impl<A, T> ShardedEngine<A, T>
+where
+    A: Storage + Send + 'static,
+    T: Storage + Send + 'static,
+{
+    // `accounts` is called once per worker to create its shard of accounts
+    pub fn new<F>(workers: usize, config: Config, mut accounts: F, transactions: T) -> Result<Self>
+    where
+        F: FnMut() -> Result<A>,
+    {
+        let transactions = Arc::new(Mutex::new(transactions));
+        let fatal = Arc::new(Mutex::new(None));
+        let mut spawned = Vec::new();
+        for _ in 0..workers.max(1) {
+            let storage = ShardStorage {
+                accounts: accounts()?,
+                transactions: Arc::clone(&transactions),
+            };
+            let engine = Engine::with_config(storage, config);
+            spawned.push(Worker::spawn(engine, Arc::clone(&fatal)));
+        }
+
+        Ok(ShardedEngine {
+            workers: spawned,
+            mentions: HashMap::new(),
+            fatal,
+        })
+    }
+
+    pub fn submit(&mut self, row: u64, tx: Transaction) -> Result<()> {
+        self.check_fatal()?;
+        if self.is_contested(&tx) {
+            self.sync()?;
+        }
+        let shard = tx.client as usize % self.workers.len();
+        self.workers[shard].send(Job::Apply(row, tx));
+        Ok(())
+    }
+
+    // waits for every worker to finish, and collects their accounts and rejected rows
+    pub fn finish(self) -> Result<ShardedOutcome> {
+        let mut accounts = Vec::new();
+        let mut rejected = Vec::new();
+        let mut violations = Vec::new();
+        for worker in self.workers {
+            let (engine, mut rows) = worker.join();
+            for account in engine.accounts() {
+                accounts.push(account?);
+            }
+            rejected.append(&mut rows);
+            violations.extend_from_slice(engine.violations());
+        }
+        if let Some(e) = lock(&self.fatal).take() {
+            return Err(e);
+        }
+
+        accounts.sort_unstable_by_key(|account| account.id);
+        rejected.sort_unstable_by_key(|rejected| rejected.row);
+        // the end of run checks have no row, and stay after the others in worker order
+        violations.sort_by_key(|violation| (violation.row.is_none(), violation.row));
+        Ok(ShardedOutcome {
+            accounts,
+            rejected,
+            violations,
+        })
+    }
+
+    fn is_contested(&mut self, tx: &Transaction) -> bool {
+        match self.mentions.get_mut(&tx.tx) {
+            None => {
+                self.mentions.insert(tx.tx.clone(), Mention::One(tx.client));
+                false
+            }
+            Some(Mention::One(client)) if *client == tx.client => false,
+            Some(mention) => {
+                *mention = Mention::Many;
+                true
+            }
+        }
+    }
+
+    fn sync(&self) -> Result<()> {
+        let (done, acks) = mpsc::channel();
+        for worker in &self.workers {
+            worker.send(Job::Sync(done.clone()));
+        }
+        drop(done);
+        // a worker that panicked drops its sender, so this can't block forever
+        let _ = acks.iter().count();
+        self.check_fatal()
+    }
+
+    fn check_fatal(&self) -> Result<()> {
+        match lock(&self.fatal).take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<A, T> Worker<A, T>
+where
+    A: Storage + Send + 'static,
+    T: Storage + Send + 'static,
+{
+    fn spawn(mut engine: Engine<ShardStorage<A, T>>, fatal: Arc<Mutex<Option<Error>>>) -> Self {
+        let (jobs, queue) = mpsc::sync_channel(QUEUE_SIZE);
+        let handle = thread::spawn(move || {
+            let mut rejected = Vec::new();
+            let mut failed = false;
+            for job in queue {
+                match job {
+                    // after a fatal error the remaining rows are only drained
+                    Job::Apply(..) if failed => {}
+                    Job::Apply(row, tx) => {
+                        let (tx_id, client) = (tx.tx.clone(), tx.client);
+                        match engine.apply_row(row, tx) {
+                            Ok(()) => {}
+                            Err(error) if error.reason_code().is_some() => {
+                                rejected.push(RejectedRow {
+                                    row,
+                                    tx: tx_id,
+                                    client,
+                                    error,
+                                });
+                            }
+                            Err(error) => {
+                                failed = true;
+                                lock(&fatal).get_or_insert(error);
+                            }
+                        }
+                    }
+                    Job::Sync(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+            if !failed {
+                if let Err(error) = engine.check_invariants().and_then(|()| engine.flush()) {
+                    lock(&fatal).get_or_insert(error);
+                }
+            }
+            (engine, rejected)
+        });
+        Worker { jobs, handle }
+    }
+
+    fn send(&self, job: Job) {
+        // the queue only closes if the worker panicked, which `join` passes on
+        let _ = self.jobs.send(job);
+    }
+
+    fn join(self) -> WorkerResult<A, T> {
+        drop(self.jobs);
+        self.handle
+            .join()
+            .unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+}
+
+pub(crate) fn lock<V>(mutex: &Mutex<V>) -> MutexGuard<'_, V> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// A worker's view of storage: its own accounts, and the transactions shared by everyone
+struct ShardStorage<A, T> {
+    accounts: A,
+    transactions: Arc<Mutex<T>>,
+}
+
+impl<A: Storage, T: Storage> Storage for ShardStorage<A, T> {
+    fn get_account(&self, client_id: u16) -> Result<Option<Account>> {
+        self.accounts.get_account(client_id)
+    }
+
+    fn insert_account(&mut self, account: &Account) -> Result<()> {
+        self.accounts.insert_account(account)
+    }
+
+    fn get_transaction(&self, tx_id: &str) -> Result<Option<Transaction>> {
+        lock(&self.transactions).get_transaction(tx_id)
+    }
+
+    fn insert_transaction(&mut self, tx: &Transaction) -> Result<()> {
+        lock(&self.transactions).insert_transaction(tx)
+    }
+
+    fn insert_event(&mut self, event: &DisputeEvent) -> Result<()> {
+        lock(&self.transactions).insert_event(event)
+    }
+
+    fn events(&self, tx_id: &str) -> Result<Vec<DisputeEvent>> {
+        lock(&self.transactions).events(tx_id)
+    }
+
+    // the journal is shared too, so entries of every shard are numbered in one sequence
+    fn append_journal(&mut self, entry: &JournalEntry) -> Result<u64> {
+        lock(&self.transactions).append_journal(entry)
+    }
+
+    fn journal(&self) -> JournalIter<'_> {
+        let entries: Vec<_> = lock(&self.transactions).journal().collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn accounts(&self) -> AccountIter<'_> {
+        self.accounts.accounts()
+    }
+
+    // every shard sees all transactions, not only those of its own clients
+    fn transactions(&self) -> TransactionIter<'_> {
+        let transactions: Vec<_> = lock(&self.transactions).transactions().collect();
+        Box::new(transactions.into_iter())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.accounts.flush()?;
+        lock(&self.transactions).flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Durability, LockPolicy, WithdrawalDisputePolicy};
+    use crate::invariants::InvariantCheck;
+    use crate::storage::{MemoryStorage, SledStorage};
+    use csv::{ReaderBuilder, Trim};
+
+    fn read_rows(csv_data: &str) -> Vec<(u64, Transaction)> {
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(csv_data.as_bytes());
+        reader
+            .deserialize::<Transaction>()
+            .enumerate()
+            .map(|(index, result)| (index as u64 + 1, result.unwrap()))
+            .collect()
+    }
+
+    // the (client, balances) and (row, reason) a run ends with, to compare modes
+    type Summary = (Vec<String>, Vec<(u64, &'static str)>);
+
+    fn sequential(rows: &[(u64, Transaction)], config: Config) -> Summary {
+        let mut engine = Engine::with_config(MemoryStorage::new(), config);
+        let mut rejected = Vec::new();
+        for (row, tx) in rows {
+            if let Err(e) = engine.apply(tx.clone()) {
+                rejected.push((*row, e.reason_code().unwrap()));
+            }
+        }
+        let accounts = engine
+            .accounts()
+            .map(|acc| format!("{:?}", acc.unwrap()))
+            .collect();
+        (accounts, rejected)
+    }
+
+    fn sharded<A, T>(
+        rows: &[(u64, Transaction)],
+        workers: usize,
+        config: Config,
+        accounts: impl FnMut() -> Result<A>,
+        transactions: T,
+    ) -> Summary
+    where
+        A: Storage + Send + 'static,
+        T: Storage + Send + 'static,
+    {
+        let mut engine = ShardedEngine::new(workers, config, accounts, transactions).unwrap();
+        for (row, tx) in rows {
+            engine.submit(*row, tx.clone()).unwrap();
+        }
+        let outcome = engine.finish().unwrap();
+        let accounts = outcome
+            .accounts
+            .iter()
+            .map(|acc| format!("{:?}", acc))
+            .collect();
+        let rejected = outcome
+            .rejected
+            .iter()
+            .map(|rejected| (rejected.row, rejected.error.reason_code().unwrap()))
+            .collect();
+        (accounts, rejected)
+    }
+
+    #[test]
+    fn test_matches_sequential_on_data_csv() {
+        let rows = read_rows(include_str!("../data.csv"));
+        let configs = [
+            Config::default(),
+            Config {
+                lock_policy: LockPolicy::AllowDisputes,
+                withdrawal_disputes: WithdrawalDisputePolicy::ProvisionalCredit,
+                durability: Durability::AtEnd,
+                invariants: InvariantCheck::Strict,
+            },
+        ];
+
+        for config in configs {
+            let expected = sequential(&rows, config);
+            for workers in [1, 2, 3, 8] {
+                let actual = sharded(
+                    &rows,
+                    workers,
+                    config,
+                    || Ok(MemoryStorage::new()),
+                    MemoryStorage::new(),
+                );
+                assert_eq!(actual, expected, "{} workers", workers);
+            }
+        }
+    }
+
+    #[test]
+    fn test_matches_sequential_with_sled_shards() {
+        let rows = read_rows(include_str!("../data.csv"));
+        let expected = sequential(&rows, Config::default());
+        let actual = sharded(
+            &rows,
+            4,
+            Config::default(),
+            SledStorage::temporary,
+            SledStorage::temporary().unwrap(),
+        );
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_tx_ids_shared_between_clients() {
+        // client 2 reuses the id of client 1's deposit, then both dispute it
+        let rows = read_rows(
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,2,1,10.0\n\
+             deposit,2,1,5.0\n\
+             dispute,2,1,\n\
+             dispute,1,1,\n\
+             withdrawal,3,2,1.0\n\
+             deposit,4,2,7.0\n\
+             dispute,4,2,\n\
+             chargeback,4,2,\n\
+             deposit,4,3,1.0\n",
+        );
+
+        let expected = sequential(&rows, Config::default());
+        for workers in [2, 3, 5] {
+            let actual = sharded(
+                &rows,
+                workers,
+                Config::default(),
+                || Ok(MemoryStorage::new()),
+                MemoryStorage::new(),
+            );
+            assert_eq!(actual, expected, "{} workers", workers);
+        }
+    }
+}
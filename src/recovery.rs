@@ -0,0 +1,272 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::account::Account;
+use crate::error::{Result, RuleViolation};
+use crate::journal::{JournalEntry, Outcome};
+use crate::storage::Storage;
+use crate::transaction::{Transaction, TxState, TxType};
+
+// A client whose stored account does not add up to what it was checked against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub client: u16,
+    // `None` when there are records for the client but no account at all
+    pub stored: Option<Account>,
+    pub expected: Account,
+}
+
+// The deposits, withdrawals and disputed transactions of a journal replay
+#[derive(Default)]
+struct Rebuilt {
+    accounts: BTreeMap<u16, Account>,
+    transactions: HashMap<String, Transaction>,
+}
+
+// Every applied row leaves a transaction record whose state tells how it affected the account,
+// so the balances can be recomputed from the records alone. Ledgers written before accounts
+// and records were committed together may have been torn by a crash between the two writes,
+// which shows up here as a client whose account and records disagree.
+pub fn check<S: Storage>(storage: &S) -> Result<Vec<Mismatch>> {
+    let mut expected: BTreeMap<u16, Account> = BTreeMap::new();
+    for tx in storage.transactions() {
+        let tx = tx?;
+        let acc = expected
+            .entry(tx.client)
+            .or_insert_with(|| Account::new(tx.client));
+        replay(&tx, acc)?;
+    }
+
+    compare(storage, expected)
+}
+
+// Rebuilds every account from nothing by replaying the applied entries of the journal, in
+// order, through the same operations the engine uses, and returns every client whose stored
+// account ends up different. Unlike `check`, this doesn't trust the transaction records either,
+// so it also catches a record and its account that were corrupted together, or a release whose
+// engine no longer applies a row the way it used to.
+//
+// Only ledgers that were journaled from their first row can be rebuilt: anything applied before
+// the journal existed shows up as a mismatch.
+pub fn rebuild<S: Storage>(storage: &S) -> Result<Vec<Mismatch>> {
+    let mut rebuilt = Rebuilt::default();
+    for entry in storage.journal() {
+        let entry = entry?;
+        if entry.outcome == Outcome::Applied {
+            rebuilt.apply(&entry);
+        }
+    }
+    compare(storage, rebuilt.accounts)
+}
+
+impl Rebuilt {
+    // an operation that fails on replay leaves the account as it was, so the client shows up
+    // as a mismatch instead of stopping the rebuild
+    fn apply(&mut self, entry: &JournalEntry) {
+        let mut acc = self
+            .accounts
+            .get(&entry.client)
+            .cloned()
+            .unwrap_or_else(|| Account::new(entry.client));
+        let replayed = match entry.event {
+            TxType::Deposit | TxType::Withdrawal => {
+                let tx = Transaction {
+                    tx_type: entry.event,
+                    client: entry.client,
+                    tx: entry.tx.clone(),
+                    amount: entry.amount,
+                    state: TxState::Processed,
+                };
+                let applied = match entry.event {
+                    TxType::Deposit => tx.deposit(&mut acc),
+                    _ => tx.withdrawal(&mut acc),
+                };
+                if applied.is_ok() {
+                    self.transactions.insert(entry.tx.clone(), tx);
+                }
+                applied
+            }
+            TxType::Dispute | TxType::Resolve | TxType::Chargeback => {
+                match self.transactions.get_mut(&entry.tx) {
+                    Some(tx) if entry.event == TxType::Dispute => tx.dispute(&mut acc),
+                    Some(tx) if entry.event == TxType::Resolve => tx.resolve(&mut acc),
+                    Some(tx) => tx.chargeback(&mut acc),
+                    None => Err(RuleViolation::UnknownTx.into()),
+                }
+            }
+        };
+        if replayed.is_ok() {
+            self.accounts.insert(entry.client, acc);
+        }
+    }
+}
+
+fn compare<S: Storage>(storage: &S, mut expected: BTreeMap<u16, Account>) -> Result<Vec<Mismatch>> {
+    let mut mismatches = Vec::new();
+    for stored in storage.accounts() {
+        let stored = stored?;
+        let expected = expected
+            .remove(&stored.id)
+            .unwrap_or_else(|| Account::new(stored.id));
+        if stored != expected {
+            mismatches.push(Mismatch {
+                client: stored.id,
+                stored: Some(stored),
+                expected,
+            });
+        }
+    }
+    mismatches.extend(expected.into_values().map(|expected| Mismatch {
+        client: expected.id,
+        stored: None,
+        expected,
+    }));
+    mismatches.sort_by_key(|mismatch| mismatch.client);
+    Ok(mismatches)
+}
+
+// applies a record as it was first processed and then walks it through its dispute history.
+// Records come in no particular order, so withdrawals skip the funds check
+fn replay(tx: &Transaction, acc: &mut Account) -> Result<()> {
+    let mut original = Transaction {
+        state: TxState::Processed,
+        ..tx.clone()
+    };
+    match tx.tx_type {
+        TxType::Withdrawal => {
+            acc.available = acc.available.checked_sub(tx.amount)?;
+            acc.total = acc.total.checked_sub(tx.amount)?;
+        }
+        _ => original.deposit(acc)?,
+    }
+    match tx.state {
+        TxState::Processed => {}
+        TxState::Disputed => original.dispute(acc)?,
+        TxState::Resolved => {
+            original.dispute(acc)?;
+            original.resolve(acc)?;
+        }
+        TxState::ChargedBack => {
+            original.dispute(acc)?;
+            original.chargeback(acc)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Config, Engine, LockPolicy, WithdrawalDisputePolicy};
+    use crate::input::InputFormat;
+    use crate::storage::{MemoryStorage, SledStorage};
+    use crate::test_util::{amt, deposit};
+    use csv::{ReaderBuilder, Trim};
+
+    #[test]
+    fn test_consistent_ledger_after_data_csv() {
+        let config = Config {
+            withdrawal_disputes: WithdrawalDisputePolicy::ProvisionalCredit,
+            ..Config::default()
+        };
+        let mut engine = Engine::with_config(SledStorage::temporary().unwrap(), config);
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(include_str!("../data.csv").as_bytes());
+        for tx in reader.deserialize::<Transaction>() {
+            let _ = engine.apply(tx.unwrap());
+        }
+
+        assert_eq!(check(engine.storage()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_rebuild_matches_after_data_csv() {
+        let config = Config {
+            lock_policy: LockPolicy::AllowDisputes,
+            withdrawal_disputes: WithdrawalDisputePolicy::ProvisionalCredit,
+            ..Config::default()
+        };
+        let mut engine = Engine::with_config(SledStorage::temporary().unwrap(), config);
+        engine
+            .process(
+                include_str!("../data.csv").as_bytes(),
+                InputFormat::Csv,
+                |_| Ok(()),
+            )
+            .unwrap();
+
+        assert_eq!(rebuild(engine.storage()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_rebuild_detects_corruption_the_records_agree_with() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut engine = Engine::new(SledStorage::open(dir.path()).unwrap());
+            engine.apply(deposit(1, "1", "10.0")).unwrap();
+            engine.apply(deposit(2, "2", "3.0")).unwrap();
+            engine.apply(deposit(2, "3", "1.0")).unwrap();
+        }
+
+        // client 1's deposit and balance corrupted the same way, which `check` can't see
+        let mut storage = SledStorage::open(dir.path()).unwrap();
+        storage
+            .insert_transaction(&deposit(1, "1", "99.0"))
+            .unwrap();
+        let mut account = Account::new(1);
+        account.available = amt("99.0");
+        account.total = amt("99.0");
+        storage.insert_account(&account).unwrap();
+
+        assert_eq!(check(&storage).unwrap(), vec![]);
+        let mismatches = rebuild(&storage).unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].stored, Some(account));
+        assert_eq!(mismatches[0].expected.total, amt("10.0"));
+    }
+
+    #[test]
+    fn test_detects_torn_writes() {
+        let mut storage = MemoryStorage::new();
+        // record written, account update lost
+        storage
+            .insert_transaction(&deposit(1, "1", "10.0"))
+            .unwrap();
+        // account written, record lost
+        let mut account = Account::new(2);
+        account.available = amt("5.0");
+        account.total = amt("5.0");
+        storage.insert_account(&account).unwrap();
+        // both written
+        let mut account = Account::new(3);
+        storage.insert_transaction(&deposit(3, "3", "1.0")).unwrap();
+        account.available = amt("1.0");
+        account.total = amt("1.0");
+        storage.insert_account(&account).unwrap();
+
+        let mismatches = check(&storage).unwrap();
+        let clients: Vec<u16> = mismatches.iter().map(|m| m.client).collect();
+        assert_eq!(clients, vec![1, 2]);
+        assert_eq!(mismatches[0].stored, None);
+        assert_eq!(mismatches[0].expected.total, amt("10.0"));
+        assert_eq!(mismatches[1].expected, Account::new(2));
+    }
+
+    #[test]
+    fn test_replays_dispute_states() {
+        let mut storage = MemoryStorage::new();
+        let mut disputed = deposit(1, "1", "10.0");
+        disputed.state = TxState::Disputed;
+        let mut charged_back = deposit(1, "2", "4.0");
+        charged_back.state = TxState::ChargedBack;
+        storage.insert_transaction(&disputed).unwrap();
+        storage.insert_transaction(&charged_back).unwrap();
+
+        let mismatches = check(&storage).unwrap();
+        let expected = &mismatches[0].expected;
+        assert_eq!(expected.available, amt("0.0"));
+        assert_eq!(expected.held, amt("10.0"));
+        assert_eq!(expected.total, amt("10.0"));
+        assert!(expected.locked);
+    }
+}
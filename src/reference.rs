@@ -0,0 +1,269 @@
+// A reference model of the ledger rules, written straight from the README and kept as plain
+// as possible, and a property test that runs random transaction streams through both the model
+// and the real engine (CSV parsing, sled storage and all) and expects the same accounts and
+// the same rejected rows out of both.
+//
+// Amounts are plain integers of ten-thousandths here, so the model doesn't share `Amount` or
+// any of the transaction operations with the engine it checks.
+
+use std::collections::{BTreeMap, HashMap};
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use crate::engine::{Config, Engine, LockPolicy, WithdrawalDisputePolicy};
+use crate::input::InputFormat;
+use crate::invariants::InvariantCheck;
+use crate::storage::SledStorage;
+use crate::transaction::TxType;
+
+#[derive(Debug, Clone)]
+struct Step {
+    event: TxType,
+    client: u16,
+    tx: u8,
+    // ten-thousandths, only used by deposits and withdrawals
+    amount: i64,
+}
+
+#[derive(Debug, Default, Clone)]
+struct ModelAccount {
+    available: i64,
+    held: i64,
+    total: i64,
+    locked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ModelState {
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+#[derive(Debug)]
+struct ModelTx {
+    kind: TxType,
+    client: u16,
+    amount: i64,
+    state: ModelState,
+}
+
+#[derive(Default)]
+struct Model {
+    allow_disputes_on_locked: bool,
+    provisional_credit: bool,
+    accounts: BTreeMap<u16, ModelAccount>,
+    txs: HashMap<u8, ModelTx>,
+}
+
+impl Model {
+    // applies one step, or returns the reason code it is rejected with
+    fn apply(&mut self, step: &Step) -> Result<(), &'static str> {
+        let mut acc = self.accounts.get(&step.client).cloned().unwrap_or_default();
+        let settles = !matches!(step.event, TxType::Deposit | TxType::Withdrawal);
+        if acc.locked && !(settles && self.allow_disputes_on_locked) {
+            return Err("ACCOUNT_LOCKED");
+        }
+
+        match self.txs.get_mut(&step.tx) {
+            None => match step.event {
+                TxType::Deposit => {
+                    acc.available += step.amount;
+                    acc.total += step.amount;
+                }
+                TxType::Withdrawal => {
+                    if step.amount > acc.available {
+                        return Err("INSUFFICIENT_FUNDS");
+                    }
+                    acc.available -= step.amount;
+                    acc.total -= step.amount;
+                }
+                _ => return Err("UNKNOWN_TX"),
+            },
+            Some(tx) => {
+                // the same deposit or withdrawal sent again changes nothing
+                if tx.kind == step.event && tx.client == step.client && tx.amount == step.amount {
+                    self.accounts.insert(step.client, acc);
+                    return Ok(());
+                }
+                if settles && tx.client != step.client {
+                    return Err("CLIENT_MISMATCH");
+                }
+                let withdrawal = tx.kind == TxType::Withdrawal;
+                if step.event == TxType::Dispute && withdrawal && !self.provisional_credit {
+                    return Err("WITHDRAWAL_DISPUTE");
+                }
+                let amount = tx.amount;
+                match (step.event, tx.state) {
+                    (TxType::Deposit | TxType::Withdrawal, _) => return Err("DUPLICATE_TX"),
+                    (TxType::Dispute, ModelState::Processed) => {
+                        tx.state = ModelState::Disputed;
+                        acc.held += amount;
+                        if withdrawal {
+                            acc.total += amount;
+                        } else {
+                            acc.available -= amount;
+                        }
+                    }
+                    (TxType::Dispute, _) => return Err("ALREADY_DISPUTED"),
+                    (TxType::Resolve, ModelState::Disputed) => {
+                        tx.state = ModelState::Resolved;
+                        acc.held -= amount;
+                        if withdrawal {
+                            acc.total -= amount;
+                        } else {
+                            acc.available += amount;
+                        }
+                    }
+                    (TxType::Chargeback, ModelState::Disputed) => {
+                        tx.state = ModelState::ChargedBack;
+                        acc.held -= amount;
+                        if withdrawal {
+                            acc.available += amount;
+                        } else {
+                            acc.total -= amount;
+                        }
+                        acc.locked = true;
+                    }
+                    (TxType::Resolve | TxType::Chargeback, _) => return Err("NOT_DISPUTED"),
+                }
+            }
+        }
+
+        if !settles {
+            self.txs.insert(
+                step.tx,
+                ModelTx {
+                    kind: step.event,
+                    client: step.client,
+                    amount: step.amount,
+                    state: ModelState::Processed,
+                },
+            );
+        }
+        self.accounts.insert(step.client, acc);
+        Ok(())
+    }
+
+    fn output(&self) -> String {
+        let mut out = String::from("client,available,held,total,locked\n");
+        for (client, acc) in &self.accounts {
+            out.push_str(&format!(
+                "{},{},{},{},{}\n",
+                client,
+                decimal(acc.available),
+                decimal(acc.held),
+                decimal(acc.total),
+                acc.locked
+            ));
+        }
+        out
+    }
+}
+
+fn decimal(units: i64) -> String {
+    let sign = if units < 0 { "-" } else { "" };
+    let units = units.unsigned_abs();
+    format!("{}{}.{:04}", sign, units / 10_000, units % 10_000)
+}
+
+fn csv_row(step: &Step) -> String {
+    let event = match step.event {
+        TxType::Deposit => "deposit",
+        TxType::Withdrawal => "withdrawal",
+        TxType::Dispute => "dispute",
+        TxType::Resolve => "resolve",
+        TxType::Chargeback => "chargeback",
+    };
+    match step.event {
+        TxType::Deposit | TxType::Withdrawal => format!(
+            "{},{},{},{}\n",
+            event,
+            step.client,
+            step.tx,
+            decimal(step.amount)
+        ),
+        _ => format!("{},{},{},\n", event, step.client, step.tx),
+    }
+}
+
+// few clients and few tx ids, so streams are full of duplicate ids, disputes of missing ids
+// and references to other clients' transactions. Most rows use the client that owns their tx
+// id though, otherwise full dispute lifecycles would hardly ever happen
+fn step() -> impl Strategy<Value = Step> {
+    let event = prop_oneof![
+        4 => Just(TxType::Deposit),
+        3 => Just(TxType::Withdrawal),
+        3 => Just(TxType::Dispute),
+        2 => Just(TxType::Resolve),
+        2 => Just(TxType::Chargeback),
+    ];
+    let client = prop_oneof![3 => Just(None), 1 => (1u16..=4).prop_map(Some)];
+    // mostly quarters, so balances often land exactly on what is withdrawn
+    let amount = prop_oneof![
+        3 => (0i64..=40).prop_map(|quarters| quarters * 2_500),
+        1 => 0i64..=500_000,
+    ];
+    (event, client, 0u8..12, amount).prop_map(|(event, client, tx, amount)| Step {
+        event,
+        client: client.unwrap_or(u16::from(tx % 4) + 1),
+        tx,
+        amount,
+    })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    #[test]
+    fn test_engine_matches_reference_model(
+        steps in vec(step(), 0..80),
+        allow_disputes_on_locked: bool,
+        provisional_credit: bool,
+    ) {
+        let mut model = Model {
+            allow_disputes_on_locked,
+            provisional_credit,
+            ..Model::default()
+        };
+        let mut expected_rejected = Vec::new();
+        for (index, step) in steps.iter().enumerate() {
+            if let Err(reason) = model.apply(step) {
+                expected_rejected.push((index as u64 + 1, reason));
+            }
+        }
+
+        let config = Config {
+            lock_policy: if allow_disputes_on_locked {
+                LockPolicy::AllowDisputes
+            } else {
+                LockPolicy::BlockAll
+            },
+            withdrawal_disputes: if provisional_credit {
+                WithdrawalDisputePolicy::ProvisionalCredit
+            } else {
+                WithdrawalDisputePolicy::Reject
+            },
+            invariants: InvariantCheck::Strict,
+            ..Config::default()
+        };
+        let input: String = std::iter::once("type,client,tx,amount\n".to_string())
+            .chain(steps.iter().map(csv_row))
+            .collect();
+        let mut engine = Engine::with_config(SledStorage::temporary().unwrap(), config);
+        let mut rejected = Vec::new();
+        engine
+            .process(input.as_bytes(), InputFormat::Csv, |rejection| {
+                rejected.push((rejection.row, rejection.reason));
+                Ok(())
+            })
+            .unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts_csv(&mut output).unwrap();
+
+        prop_assert_eq!(String::from_utf8(output).unwrap(), model.output());
+        prop_assert_eq!(rejected, expected_rejected);
+    }
+}
@@ -0,0 +1,147 @@
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+// One input row that was not applied to the ledger
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Rejection {
+    // 1-based index of the data row, the header is not counted
+    pub row: u64,
+    pub tx: Option<String>,
+    pub client: Option<u16>,
+    pub reason: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Csv,
+    Jsonl,
+}
+
+// Writes rejections to a CSV or JSON Lines file as they happen
+pub struct RejectionWriter<W: Write> {
+    sink: Sink<W>,
+}
+
+enum Sink<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Jsonl(W),
+}
+
+impl Rejection {
+    // `None` when the error is not about the row itself (storage, I/O...) and must abort the run
+    pub fn new(
+        row: u64,
+        tx: Option<String>,
+        client: Option<u16>,
+        err: &Error,
+    ) -> Option<Rejection> {
+        let reason = err.reason_code()?;
+        Some(Rejection {
+            row,
+            tx,
+            client,
+            reason,
+            message: err.to_string(),
+        })
+    }
+}
+
+impl<W: Write> RejectionWriter<W> {
+    pub fn new(writer: W, format: ReportFormat) -> RejectionWriter<W> {
+        let sink = match format {
+            ReportFormat::Csv => Sink::Csv(Box::new(csv::Writer::from_writer(writer))),
+            ReportFormat::Jsonl => Sink::Jsonl(writer),
+        };
+        RejectionWriter { sink }
+    }
+
+    pub fn write(&mut self, rejection: &Rejection) -> Result<()> {
+        match &mut self.sink {
+            Sink::Csv(wtr) => wtr.serialize(rejection)?,
+            Sink::Jsonl(wtr) => {
+                serde_json::to_writer(&mut *wtr, rejection)?;
+                wtr.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        match &mut self.sink {
+            Sink::Csv(wtr) => wtr.flush()?,
+            Sink::Jsonl(wtr) => wtr.flush()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RuleViolation;
+
+    fn rejections() -> Vec<Rejection> {
+        vec![
+            Rejection::new(
+                3,
+                Some("7".to_string()),
+                Some(2),
+                &RuleViolation::InsufficientFunds.into(),
+            )
+            .unwrap(),
+            Rejection::new(4, None, None, &Error::Parse("bad row".to_string())).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_fatal_errors_are_not_rejections() {
+        let err = Error::Io(std::io::Error::other("disk full"));
+        assert!(Rejection::new(1, None, None, &err).is_none());
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let mut buffer = Vec::new();
+        {
+            let mut writer = RejectionWriter::new(&mut buffer, ReportFormat::Csv);
+            for rejection in rejections() {
+                writer.write(&rejection).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+
+        let output = String::from_utf8(buffer).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "row,tx,client,reason,message");
+        assert!(lines[1].starts_with("3,7,2,INSUFFICIENT_FUNDS,"));
+        assert!(lines[2].starts_with("4,,,PARSE_ERROR,"));
+    }
+
+    #[test]
+    fn test_write_jsonl() {
+        let mut buffer = Vec::new();
+        {
+            let mut writer = RejectionWriter::new(&mut buffer, ReportFormat::Jsonl);
+            for rejection in rejections() {
+                writer.write(&rejection).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+
+        let output = String::from_utf8(buffer).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["reason"], "INSUFFICIENT_FUNDS");
+        assert_eq!(lines[0]["tx"], "7");
+        assert_eq!(lines[0]["client"], 2);
+        assert_eq!(lines[1]["reason"], "PARSE_ERROR");
+        assert!(lines[1]["tx"].is_null());
+    }
+}
@@ -0,0 +1,236 @@
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::engine::Engine;
+use crate::error::Result;
+use crate::input::{self, COLUMNS};
+use crate::parallel::lock;
+use crate::report::Rejection;
+use crate::storage::Storage;
+
+// how often a server flushes the rows its durability setting left unflushed
+pub(crate) const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// the longest line read, newline included. Rows are far shorter; anything longer is dropped
+// without being held in memory
+const MAX_LINE: u64 = 4096;
+
+// Accepts transaction rows over TCP and applies them to one shared ledger.
+//
+// Every connection sends newline-delimited CSV rows in `COLUMNS` order and gets one line back
+// for each line it sent, in the same order:
+//
+//   type,client,tx,amount  ->  skip 1
+//   deposit,1,2,10.0       ->  ack 2
+//   withdrawal,1,3,50      ->  nack 3 INSUFFICIENT_FUNDS Transaction rejected: insufficient ...
+//   query 1                ->  account 1,10.0000,0.0000,10.0000,false
+//
+// Blank and header lines are skipped, and lines over `MAX_LINE` bytes rejected unread, but they
+// still get their reply, so a producer counting replies never falls out of step.
+// Lines are numbered from 1 per connection. Rows are applied one at a time, so the rows a
+// producer sends for a client are applied in the order it sent them.
+pub struct Server<S: Storage> {
+    listener: TcpListener,
+    engine: Arc<Mutex<Engine<S>>>,
+}
+
+impl<S: Storage + Send + 'static> Server<S> {
+    pub fn bind<A: ToSocketAddrs>(addr: A, engine: Engine<S>) -> Result<Server<S>> {
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            engine: Arc::new(Mutex::new(engine)),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // serves every connection on a thread of its own, until accepting fails
+    pub fn run(self) -> Result<()> {
+        flush_periodically(Arc::clone(&self.engine));
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let engine = Arc::clone(&self.engine);
+            thread::spawn(move || {
+                // a connection that fails only takes itself down
+                let _ = handle(stream, &engine);
+            });
+        }
+        Ok(())
+    }
+}
+
+// A server never reaches the end of its input, so with `Durability::EveryN` or `AtEnd` some
+// rows would only ever be flushed by the storage's own background work. Whatever is pending is
+// flushed every `FLUSH_INTERVAL` instead. A failed flush is tried again at the next tick
+pub(crate) fn flush_periodically<S>(engine: Arc<Mutex<Engine<S>>>)
+where
+    S: Storage + Send + 'static,
+{
+    thread::spawn(move || loop {
+        thread::sleep(FLUSH_INTERVAL);
+        let _ = lock(&engine).flush_pending();
+    });
+}
+
+fn handle<S: Storage>(stream: TcpStream, engine: &Mutex<Engine<S>>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let header = COLUMNS.join(",");
+    let mut line = Vec::new();
+    let mut number = 0;
+
+    loop {
+        line.clear();
+        let read = (&mut reader).take(MAX_LINE).read_until(b'\n', &mut line)?;
+        if read == 0 {
+            return Ok(());
+        }
+        number += 1;
+        let reply = if !line.ends_with(b"\n") && read as u64 == MAX_LINE {
+            reader.skip_until(b'\n')?;
+            let message = format!("line is longer than {} bytes", MAX_LINE);
+            nack(number, "LINE_TOO_LONG", &message)
+        } else {
+            match std::str::from_utf8(&line).map(str::trim) {
+                Err(_) => nack(number, "PARSE_ERROR", "line is not valid UTF-8"),
+                Ok("") => format!("skip {}", number),
+                Ok(row) if row.replace(' ', "") == header => format!("skip {}", number),
+                Ok(row) => match row.strip_prefix("query ") {
+                    Some(client) => query(engine, number, client.trim())?,
+                    None => apply(engine, number, row)?,
+                },
+            }
+        };
+        writeln!(writer, "{}", reply)?;
+        writer.flush()?;
+    }
+}
+
+fn apply<S: Storage>(engine: &Mutex<Engine<S>>, number: u64, line: &str) -> Result<String> {
+    let row = input::parse_line(number, line);
+    match row.parsed.and_then(|tx| lock(engine).apply(tx)) {
+        Ok(()) => Ok(format!("ack {}", number)),
+        Err(err) => {
+            // a storage failure is not the row's fault: the connection is dropped instead
+            let rejection = Rejection::new(number, row.tx, row.client, &err).ok_or(err)?;
+            Ok(nack(number, rejection.reason, &rejection.message))
+        }
+    }
+}
+
+fn query<S: Storage>(engine: &Mutex<Engine<S>>, number: u64, client: &str) -> Result<String> {
+    let Ok(client) = client.parse::<u16>() else {
+        return Ok(nack(number, "PARSE_ERROR", "client must be a number"));
+    };
+    match lock(engine).account(client)? {
+        Some(acc) => Ok(format!(
+            "account {},{},{},{},{}",
+            acc.id, acc.available, acc.held, acc.total, acc.locked
+        )),
+        None => Ok(nack(number, "UNKNOWN_CLIENT", "no account for this client")),
+    }
+}
+
+fn nack(number: u64, reason: &str, message: &str) -> String {
+    format!("nack {} {} {}", number, reason, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::io::BufRead;
+
+    fn start() -> SocketAddr {
+        let server = Server::bind("127.0.0.1:0", Engine::new(MemoryStorage::new())).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        addr
+    }
+
+    // sends every line, then reads back as many replies as expected
+    fn exchange(addr: SocketAddr, lines: &[&str], replies: usize) -> Vec<String> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        for line in lines {
+            writeln!(stream, "{}", line).unwrap();
+        }
+        let reader = BufReader::new(stream);
+        reader.lines().take(replies).map(|l| l.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_ack_nack_and_query() {
+        let addr = start();
+        let replies = exchange(
+            addr,
+            &[
+                "type, client, tx, amount",
+                "deposit, 1, 1, 10.0",
+                "withdrawal, 1, 2, 50.0",
+                "refund, 1, 3, 1.0",
+                "dispute, 1, 1,",
+                "query 1",
+                "query 2",
+                "",
+                "query 1",
+            ],
+            9,
+        );
+
+        assert_eq!(replies[0], "skip 1");
+        assert_eq!(replies[1], "ack 2");
+        assert!(replies[2].starts_with("nack 3 INSUFFICIENT_FUNDS "));
+        assert!(replies[3].starts_with("nack 4 PARSE_ERROR "));
+        assert_eq!(replies[4], "ack 5");
+        assert_eq!(replies[5], "account 1,0.0000,10.0000,10.0000,false");
+        assert!(replies[6].starts_with("nack 7 UNKNOWN_CLIENT "));
+        assert_eq!(replies[7], "skip 8");
+        assert!(replies[8].starts_with("account 1,"));
+    }
+
+    #[test]
+    fn test_long_lines_are_rejected() {
+        let addr = start();
+        let long = format!("deposit,1,1,1.{}", "0".repeat(100_000));
+        let exact = format!("deposit,1,2,1.{}", "0".repeat(MAX_LINE as usize - 15));
+        let replies = exchange(addr, &[&long, "deposit,1,3,1.0", &exact, "query 1"], 4);
+
+        assert_eq!(
+            replies[0],
+            format!(
+                "nack 1 LINE_TOO_LONG line is longer than {} bytes",
+                MAX_LINE
+            )
+        );
+        assert_eq!(replies[1], "ack 2");
+        assert!(replies[2].starts_with("nack 3 PARSE_ERROR "));
+        assert_eq!(replies[3], "account 1,1.0000,0.0000,1.0000,false");
+    }
+
+    #[test]
+    fn test_connections_share_the_ledger() {
+        let addr = start();
+        let producers: Vec<_> = (0..4u16)
+            .map(|client| {
+                thread::spawn(move || {
+                    let lines: Vec<String> = (0..50)
+                        .map(|i| format!("deposit,{},{}-{},1.0", client, client, i))
+                        .collect();
+                    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+                    exchange(addr, &lines, lines.len())
+                })
+            })
+            .collect();
+        for producer in producers {
+            let replies = producer.join().unwrap();
+            assert!(replies.iter().all(|reply| reply.starts_with("ack ")));
+        }
+
+        let replies = exchange(addr, &["query 3"], 1);
+        assert_eq!(replies[0], "account 3,50.0000,0.0000,50.0000,false");
+    }
+}
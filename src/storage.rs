@@ -0,0 +1,75 @@
+mod memory;
+mod sled_store;
+
+use crate::account::Account;
+use crate::error::Result;
+use crate::journal::JournalEntry;
+use crate::transaction::{DisputeEvent, Transaction};
+
+pub use memory::MemoryStorage;
+pub use sled_store::SledStorage;
+
+pub type AccountIter<'a> = Box<dyn Iterator<Item = Result<Account>> + 'a>;
+pub type TransactionIter<'a> = Box<dyn Iterator<Item = Result<Transaction>> + 'a>;
+pub type JournalIter<'a> = Box<dyn Iterator<Item = Result<JournalEntry>> + 'a>;
+
+// Everything one applied row changes, written together by `Storage::commit`
+#[derive(Debug, Clone, Copy)]
+pub struct Commit<'a> {
+    pub account: &'a Account,
+    // the deposit or withdrawal, new or with its dispute state updated. `None` when the row
+    // was a replay of one already applied
+    pub tx: Option<&'a Transaction>,
+    // set for disputes, resolves and chargebacks
+    pub event: Option<&'a DisputeEvent>,
+    // appended to the journal like with `Storage::append_journal`
+    pub journal: &'a JournalEntry,
+}
+
+// Where the engine keeps accounts and transactions between rows
+pub trait Storage {
+    fn get_account(&self, client_id: u16) -> Result<Option<Account>>;
+
+    fn insert_account(&mut self, account: &Account) -> Result<()>;
+
+    fn get_transaction(&self, tx_id: &str) -> Result<Option<Transaction>>;
+
+    fn insert_transaction(&mut self, tx: &Transaction) -> Result<()>;
+
+    fn insert_event(&mut self, event: &DisputeEvent) -> Result<()>;
+
+    // every event recorded for a transaction, in sequence order
+    fn events(&self, tx_id: &str) -> Result<Vec<DisputeEvent>>;
+
+    // adds an entry at the end of the journal and returns the sequence number it was given,
+    // whatever `entry.seq` was set to
+    fn append_journal(&mut self, entry: &JournalEntry) -> Result<u64>;
+
+    // every journal entry, in sequence order
+    fn journal(&self) -> JournalIter<'_>;
+
+    // writes everything a row changed. Backends that can crash halfway should write it all
+    // atomically
+    fn commit(&mut self, commit: &Commit) -> Result<()> {
+        if let Some(event) = commit.event {
+            self.insert_event(event)?;
+        }
+        if let Some(tx) = commit.tx {
+            self.insert_transaction(tx)?;
+        }
+        self.insert_account(commit.account)?;
+        self.append_journal(commit.journal)?;
+        Ok(())
+    }
+
+    // accounts must be yielded in client id order, so outputs are the same for every backend
+    fn accounts(&self) -> AccountIter<'_>;
+
+    // transactions can come in any order
+    fn transactions(&self) -> TransactionIter<'_>;
+
+    // makes every write so far durable; backends that don't persist anything have nothing to do
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
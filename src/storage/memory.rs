@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use crate::account::Account;
+use crate::error::Result;
+use crate::journal::JournalEntry;
+use crate::storage::{AccountIter, JournalIter, Storage, TransactionIter};
+use crate::transaction::{DisputeEvent, Transaction};
+
+// Keeps everything in memory, for batches small enough that going to disk is not worth it
+#[derive(Default)]
+pub struct MemoryStorage {
+    accounts: HashMap<u16, Account>,
+    transactions: HashMap<String, Transaction>,
+    events: HashMap<String, Vec<DisputeEvent>>,
+    journal: Vec<JournalEntry>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get_account(&self, client_id: u16) -> Result<Option<Account>> {
+        Ok(self.accounts.get(&client_id).cloned())
+    }
+
+    fn insert_account(&mut self, account: &Account) -> Result<()> {
+        self.accounts.insert(account.id, account.clone());
+        Ok(())
+    }
+
+    fn get_transaction(&self, tx_id: &str) -> Result<Option<Transaction>> {
+        Ok(self.transactions.get(tx_id).cloned())
+    }
+
+    fn insert_transaction(&mut self, tx: &Transaction) -> Result<()> {
+        self.transactions.insert(tx.tx.clone(), tx.clone());
+        Ok(())
+    }
+
+    fn insert_event(&mut self, event: &DisputeEvent) -> Result<()> {
+        let events = self.events.entry(event.tx.clone()).or_default();
+        events.retain(|recorded| recorded.seq != event.seq);
+        events.push(event.clone());
+        events.sort_by_key(|recorded| recorded.seq);
+        Ok(())
+    }
+
+    fn events(&self, tx_id: &str) -> Result<Vec<DisputeEvent>> {
+        Ok(self.events.get(tx_id).cloned().unwrap_or_default())
+    }
+
+    fn append_journal(&mut self, entry: &JournalEntry) -> Result<u64> {
+        let seq = self.journal.len() as u64 + 1;
+        self.journal.push(JournalEntry {
+            seq,
+            ..entry.clone()
+        });
+        Ok(seq)
+    }
+
+    fn journal(&self) -> JournalIter<'_> {
+        Box::new(self.journal.iter().cloned().map(Ok))
+    }
+
+    fn accounts(&self) -> AccountIter<'_> {
+        let mut ids: Vec<u16> = self.accounts.keys().copied().collect();
+        ids.sort_unstable();
+        Box::new(ids.into_iter().map(|id| Ok(self.accounts[&id].clone())))
+    }
+
+    fn transactions(&self) -> TransactionIter<'_> {
+        Box::new(self.transactions.values().cloned().map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::amt;
+    use crate::transaction::{TxState, TxType};
+
+    #[test]
+    fn test_insert_and_get_account() {
+        let mut storage = MemoryStorage::new();
+        assert!(storage.get_account(1).unwrap().is_none());
+
+        let mut account = Account::new(1);
+        account.total = amt("100.0");
+        storage.insert_account(&account).unwrap();
+
+        let fetched_account = storage.get_account(1).unwrap().unwrap();
+        assert_eq!(fetched_account.id, 1);
+        assert_eq!(fetched_account.total, amt("100.0"));
+    }
+
+    #[test]
+    fn test_insert_and_get_transaction() {
+        let mut storage = MemoryStorage::new();
+
+        let transaction = Transaction {
+            tx_type: TxType::Deposit,
+            client: 1,
+            tx: "tx1".to_string(),
+            amount: amt("100.0"),
+            state: TxState::Processed,
+        };
+
+        storage.insert_transaction(&transaction).unwrap();
+        let fetched_transaction = storage.get_transaction("tx1").unwrap().unwrap();
+        assert_eq!(fetched_transaction.client, 1);
+        assert_eq!(fetched_transaction.amount, amt("100.0"));
+        assert!(storage.get_transaction("tx2").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_accounts_sorted_by_client() {
+        let mut storage = MemoryStorage::new();
+        for id in [300, 7, 42, 1] {
+            storage.insert_account(&Account::new(id)).unwrap();
+        }
+
+        let ids: Vec<u16> = storage.accounts().map(|acc| acc.unwrap().id).collect();
+        assert_eq!(ids, vec![1, 7, 42, 300]);
+    }
+}
@@ -0,0 +1,868 @@
+use std::fs::{create_dir_all, remove_dir_all, File};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+
+use fs2::FileExt;
+use serde_json::{from_slice, to_string, to_value, to_vec, Map, Value};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Batch, Config, Db, Transactional, Tree};
+use tempfile::TempDir;
+
+use crate::account::Account;
+use crate::amount::{Amount, AmountError};
+use crate::error::{Error, Result};
+use crate::journal::JournalEntry;
+use crate::storage::{AccountIter, Commit, JournalIter, Storage, TransactionIter};
+use crate::transaction::{DisputeEvent, Transaction, TxState};
+
+// One K/V database on disk, with a tree for Accounts, one for Transactions, one for dispute
+// events and one for the journal, so all the writes for a row go into a single sled transaction
+// and can never be torn apart by a crash
+pub struct SledStorage {
+    db: Db,
+    accounts: Tree,
+    transactions: Tree,
+    events: Tree,
+    journal: Tree,
+    // sequence number of the last journal entry
+    journal_seq: u64,
+    migrated: bool,
+    // held for as long as the ledger is open, so no other process can use it at the same time
+    _lock: Option<File>,
+    // the unique directory of a temporary ledger, deleted after the database is closed
+    _dir: Option<TempDir>,
+}
+
+impl SledStorage {
+    pub const DB_NAME: &'static str = "ledger_db";
+    const LOCK_NAME: &'static str = "ledger.lock";
+    const ACCOUNTS_TREE: &'static str = "accounts";
+    const TRANSACTIONS_TREE: &'static str = "transactions";
+    const EVENTS_TREE: &'static str = "events";
+    const JOURNAL_TREE: &'static str = "journal";
+    // older versions kept each kind of record in a database of its own
+    const LEGACY_ACCOUNT_DB: &'static str = "account_db";
+    const LEGACY_TRANSACTION_DB: &'static str = "transation_db";
+    // how long `open` waits for sled to let go of a database this process just closed
+    const REOPEN_ATTEMPTS: u32 = 200;
+    const REOPEN_DELAY: Duration = Duration::from_millis(10);
+
+    pub fn new(db: Db) -> Result<SledStorage> {
+        let journal = db.open_tree(SledStorage::JOURNAL_TREE)?;
+        let journal_seq = match journal.last()? {
+            Some((key, _)) => journal_seq(&key)?,
+            None => 0,
+        };
+        Ok(SledStorage {
+            accounts: db.open_tree(SledStorage::ACCOUNTS_TREE)?,
+            transactions: db.open_tree(SledStorage::TRANSACTIONS_TREE)?,
+            events: db.open_tree(SledStorage::EVENTS_TREE)?,
+            journal,
+            journal_seq,
+            db,
+            migrated: false,
+            _lock: None,
+            _dir: None,
+        })
+    }
+
+    // opens (or creates) the database inside `dir`, keeping whatever state it already holds.
+    // A ledger left in the old two-database layout is moved into it first.
+    // Fails with `Error::Locked` while another process has the same ledger open
+    pub fn open(dir: &Path) -> Result<SledStorage> {
+        let lock = SledStorage::lock(dir)?;
+        let mut storage = SledStorage::new(SledStorage::open_db(&dir.join(SledStorage::DB_NAME))?)?;
+        storage._lock = Some(lock);
+        storage.migrated = storage.import_legacy(dir)?;
+        Ok(storage)
+    }
+
+    // deletes the database inside `dir`, leaving anything else in it untouched. The lock file
+    // stays: deleting it would let one process lock the old file while another creates and
+    // locks a new one
+    pub fn remove(dir: &Path) -> Result<()> {
+        if !dir.exists() {
+            return Ok(());
+        }
+        let _lock = SledStorage::lock(dir)?;
+        for name in [
+            SledStorage::DB_NAME,
+            SledStorage::LEGACY_ACCOUNT_DB,
+            SledStorage::LEGACY_TRANSACTION_DB,
+        ] {
+            match remove_dir_all(dir.join(name)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // the database is removed when the storage is dropped
+    pub fn temporary() -> Result<SledStorage> {
+        SledStorage::new(Config::new().temporary(true).open()?)
+    }
+
+    // like `temporary`, but in a directory of its own inside `dir` that nothing else uses, so
+    // runs side by side never share state and a crashed run never leaks into the next one
+    pub fn temporary_in(dir: &Path) -> Result<SledStorage> {
+        create_dir_all(dir)?;
+        let run_dir = tempfile::Builder::new()
+            .prefix("tx_processing-")
+            .tempdir_in(dir)?;
+        let db = Config::new()
+            .path(run_dir.path().join(SledStorage::DB_NAME))
+            .temporary(true)
+            .open()?;
+        let mut storage = SledStorage::new(db)?;
+        storage._dir = Some(run_dir);
+        Ok(storage)
+    }
+
+    // sled's background flusher can keep the database file locked for a moment after the last
+    // handle to it is dropped, so a ledger closed by this process can't always be reopened
+    // straight away. `open` holds the ledger lock by then, so nobody else can be holding the
+    // database: it is only a matter of waiting for the flusher to finish
+    fn open_db(path: &Path) -> Result<Db> {
+        let mut attempts = 1;
+        loop {
+            match sled::open(path) {
+                Err(e) if is_lock_error(&e) && attempts < SledStorage::REOPEN_ATTEMPTS => {
+                    attempts += 1;
+                    sleep(SledStorage::REOPEN_DELAY);
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    fn lock(dir: &Path) -> Result<File> {
+        create_dir_all(dir)?;
+        let file = File::create(dir.join(SledStorage::LOCK_NAME))?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(file),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                Err(Error::Locked(dir.to_path_buf()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // whether `open` found a ledger in the old layout. Those were written without atomic
+    // commits, so it is worth running `recovery::check` on them
+    pub fn migrated_legacy(&self) -> bool {
+        self.migrated
+    }
+
+    fn import_legacy(&self, dir: &Path) -> Result<bool> {
+        let legacy = [
+            (SledStorage::LEGACY_ACCOUNT_DB, &self.accounts),
+            (SledStorage::LEGACY_TRANSACTION_DB, &self.transactions),
+        ];
+        // every record is upgraded and read back before anything is written, so one this
+        // version can't read leaves the old databases as they were instead of half imported
+        let mut batches = Vec::new();
+        for (name, tree) in legacy {
+            let path = dir.join(name);
+            if !path.exists() {
+                continue;
+            }
+            let mut batch = Batch::default();
+            for entry in SledStorage::open_db(&path)?.iter() {
+                let (key, value) = entry?;
+                let record = upgrade_record(name, &value).map_err(|e| {
+                    Error::Storage(sled::Error::Unsupported(format!(
+                        "a record in {} can't be imported, so the old ledger was left as it was: {}",
+                        path.display(),
+                        e
+                    )))
+                })?;
+                batch.insert(key, record);
+            }
+            batches.push((tree, batch));
+        }
+        if batches.is_empty() {
+            return Ok(false);
+        }
+        for (tree, batch) in batches {
+            tree.apply_batch(batch)?;
+        }
+        // the old databases only go away once their records are safely in the new one,
+        // so a crash halfway through just imports them again next time
+        self.db.flush()?;
+        for (name, _) in legacy {
+            match remove_dir_all(dir.join(name)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Storage for SledStorage {
+    fn get_account(&self, client_id: u16) -> Result<Option<Account>> {
+        if let Some(serialized_data) = self.accounts.get(client_id.to_be_bytes())? {
+            let account: Account = from_slice(&serialized_data)?;
+            Ok(Some(account))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn insert_account(&mut self, account: &Account) -> Result<()> {
+        let serialized_data = to_string(account)?;
+        self.accounts
+            .insert(account.id.to_be_bytes(), serialized_data.as_bytes())?;
+        Ok(())
+    }
+
+    fn get_transaction(&self, tx_id: &str) -> Result<Option<Transaction>> {
+        if let Some(serialized_data) = self.transactions.get(tx_id.as_bytes())? {
+            let tx: Transaction = from_slice(&serialized_data)?;
+            Ok(Some(tx))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn insert_transaction(&mut self, tx: &Transaction) -> Result<()> {
+        let serialized_data = to_string(tx)?;
+        self.transactions
+            .insert(tx.tx.as_bytes(), serialized_data.as_bytes())?;
+        Ok(())
+    }
+
+    fn insert_event(&mut self, event: &DisputeEvent) -> Result<()> {
+        self.events
+            .insert(event_key(&event.tx, event.seq), to_vec(event)?)?;
+        Ok(())
+    }
+
+    fn events(&self, tx_id: &str) -> Result<Vec<DisputeEvent>> {
+        self.events
+            .scan_prefix(event_prefix(tx_id))
+            .map(|result| {
+                let (_, value) = result?;
+                Ok(from_slice(&value)?)
+            })
+            .collect()
+    }
+
+    fn append_journal(&mut self, entry: &JournalEntry) -> Result<u64> {
+        let seq = self.journal_seq + 1;
+        let entry = JournalEntry {
+            seq,
+            ..entry.clone()
+        };
+        self.journal.insert(seq.to_be_bytes(), to_vec(&entry)?)?;
+        self.journal_seq = seq;
+        Ok(seq)
+    }
+
+    // big-endian keys make sled iterate in sequence order
+    fn journal(&self) -> JournalIter<'_> {
+        Box::new(self.journal.iter().map(|result| {
+            let (_, value) = result?;
+            let entry: JournalEntry = from_slice(&value)?;
+            Ok(entry)
+        }))
+    }
+
+    // all trees are written in one sled transaction: after a crash either every write of the
+    // row is there or none is
+    fn commit(&mut self, commit: &Commit) -> Result<()> {
+        let account = commit.account;
+        let account_data = to_vec(account)?;
+        let tx_data = commit
+            .tx
+            .map(|tx| Ok::<_, Error>((tx.tx.as_bytes(), to_vec(tx)?)))
+            .transpose()?;
+        let event_data = commit
+            .event
+            .map(|event| Ok::<_, Error>((event_key(&event.tx, event.seq), to_vec(event)?)))
+            .transpose()?;
+        let seq = self.journal_seq + 1;
+        let journal_data = to_vec(&JournalEntry {
+            seq,
+            ..commit.journal.clone()
+        })?;
+
+        let trees = (
+            &self.accounts,
+            &self.transactions,
+            &self.events,
+            &self.journal,
+        );
+        let result = trees.transaction(|(accounts, transactions, events, journal)| {
+            accounts.insert(&account.id.to_be_bytes(), account_data.as_slice())?;
+            if let Some((key, value)) = &tx_data {
+                transactions.insert(*key, value.as_slice())?;
+            }
+            if let Some((key, value)) = &event_data {
+                events.insert(key.as_slice(), value.as_slice())?;
+            }
+            journal.insert(&seq.to_be_bytes(), journal_data.as_slice())?;
+            Ok::<(), ConflictableTransactionError>(())
+        });
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) | TransactionError::Storage(e) => Error::from(e),
+        })?;
+        self.journal_seq = seq;
+        Ok(())
+    }
+
+    // big-endian keys make sled iterate in client id order
+    fn accounts(&self) -> AccountIter<'_> {
+        Box::new(self.accounts.iter().map(|result| {
+            let (_, value) = result?;
+            let account: Account = from_slice(&value)?;
+            Ok(account)
+        }))
+    }
+
+    fn transactions(&self) -> TransactionIter<'_> {
+        Box::new(self.transactions.iter().map(|result| {
+            let (_, value) = result?;
+            let tx: Transaction = from_slice(&value)?;
+            Ok(tx)
+        }))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+// Brings a record of the old layout up to date, and makes sure this version can read it
+fn upgrade_record(name: &str, value: &[u8]) -> Result<Vec<u8>> {
+    let mut record: Map<String, Value> = from_slice(value)?;
+    if name == SledStorage::LEGACY_TRANSACTION_DB {
+        upgrade_dispute_flag(&mut record)?;
+        upgrade_amounts(&mut record, &["amount"])?;
+        let upgraded = to_vec(&record)?;
+        from_slice::<Transaction>(&upgraded)?;
+        Ok(upgraded)
+    } else {
+        upgrade_amounts(&mut record, &["available", "held", "total"])?;
+        let upgraded = to_vec(&record)?;
+        from_slice::<Account>(&upgraded)?;
+        Ok(upgraded)
+    }
+}
+
+// Records written before the dispute state machine have an `under_dispute` flag, stored as
+// "true" or "false", instead of a state. Left alone, the flag would be ignored and an open
+// dispute read back as processed, with its funds held for good
+fn upgrade_dispute_flag(record: &mut Map<String, Value>) -> Result<()> {
+    let Some(flag) = record.remove("under_dispute") else {
+        return Ok(());
+    };
+    let state = if flag == "true" || flag == true {
+        TxState::Disputed
+    } else {
+        TxState::Processed
+    };
+    record.insert("state".to_string(), to_value(state)?);
+    Ok(())
+}
+
+// The first versions stored amounts as f32 numbers rather than decimal strings
+fn upgrade_amounts(record: &mut Map<String, Value>, fields: &[&str]) -> Result<()> {
+    for field in fields {
+        if let Some(Value::Number(number)) = record.get(*field) {
+            let text = number.to_string();
+            let amount = legacy_amount(&text).ok_or_else(|| AmountError::Invalid(text.clone()))?;
+            record.insert(field.to_string(), to_value(amount)?);
+        }
+    }
+    Ok(())
+}
+
+// An f32 is written with as many digits as it takes to tell it apart from its neighbours, so
+// 1.1 + 2.2 comes out as `3.3000002`. Rounds the number as written, half away from zero, to
+// the decimals an `Amount` keeps. `None` if it isn't a number or doesn't fit
+fn legacy_amount(number: &str) -> Option<Amount> {
+    let (negative, unsigned) = match number.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, number),
+    };
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().ok()?),
+        None => (unsigned, 0),
+    };
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits: Vec<u8> = int.bytes().chain(frac.bytes()).collect();
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    // how many of the digits, padded with zeros, make up the ten-thousandths
+    let kept = int.len() as i64 + exponent + i64::from(Amount::DECIMALS);
+    let mut units: i64 = 0;
+    // past the digits only zeros follow, and more than 19 of them overflow anything but 0
+    for index in 0..kept.clamp(0, digits.len() as i64 + 19) {
+        let digit = digits.get(index as usize).map_or(0, |digit| digit - b'0');
+        units = units.checked_mul(10)?.checked_add(i64::from(digit))?;
+    }
+    let next = usize::try_from(kept)
+        .ok()
+        .and_then(|index| digits.get(index));
+    if next.is_some_and(|digit| *digit >= b'5') {
+        units = units.checked_add(1)?;
+    }
+    Some(Amount::from_scaled(if negative { -units } else { units }))
+}
+
+// Event keys are the tx id prefixed by its length, then the big-endian sequence number. The
+// length keeps the events of `5` apart from those of `55`, and the sequence number keeps them
+// in order within a transaction.
+fn event_prefix(tx_id: &str) -> Vec<u8> {
+    let mut key = (tx_id.len() as u32).to_be_bytes().to_vec();
+    key.extend_from_slice(tx_id.as_bytes());
+    key
+}
+
+fn event_key(tx_id: &str, seq: u32) -> Vec<u8> {
+    let mut key = event_prefix(tx_id);
+    key.extend_from_slice(&seq.to_be_bytes());
+    key
+}
+
+// whether sled failed to open a database because its file is locked. sled has no error kind for
+// that, only the message of the `Io` error, which this matches as written by sled 0.34.7;
+// `test_lock_error_is_recognized` breaks if an upgrade changes it
+fn is_lock_error(err: &sled::Error) -> bool {
+    match err {
+        sled::Error::Io(e) => e.to_string().starts_with("could not acquire lock"),
+        _ => false,
+    }
+}
+
+fn journal_seq(key: &[u8]) -> Result<u64> {
+    let bytes = key
+        .try_into()
+        .map_err(|_| Error::Storage(sled::Error::Unsupported("bad journal key".to_string())))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::test_util::amt;
+    use crate::transaction::TxType;
+    use serde::Serialize;
+    use serde_json::json;
+
+    #[test]
+    fn test_insert_and_get_account() {
+        let mut storage = SledStorage::temporary().unwrap();
+
+        let account = Account {
+            id: 1,
+            total: amt("100.0"),
+            available: amt("100.0"),
+            held: amt("0.0"),
+            locked: false,
+        };
+
+        storage.insert_account(&account).unwrap();
+        let fetched_account = storage.get_account(1).unwrap().unwrap();
+
+        assert_eq!(fetched_account.id, 1);
+        assert_eq!(fetched_account.total, amt("100.0"));
+        assert_eq!(fetched_account.available, amt("100.0"));
+        assert_eq!(fetched_account.held, amt("0.0"));
+        assert!(!fetched_account.locked);
+    }
+
+    #[test]
+    fn test_insert_and_get_transaction() {
+        let mut storage = SledStorage::temporary().unwrap();
+
+        let transaction = Transaction {
+            tx_type: TxType::Deposit,
+            client: 1,
+            tx: "tx1".to_string(),
+            amount: amt("100.0"),
+            state: TxState::Processed,
+        };
+
+        storage.insert_transaction(&transaction).unwrap();
+        let fetched_transaction = storage.get_transaction("tx1").unwrap().unwrap();
+
+        assert_eq!(fetched_transaction.tx_type, TxType::Deposit);
+        assert_eq!(fetched_transaction.client, 1);
+        assert_eq!(fetched_transaction.tx, "tx1");
+        assert_eq!(fetched_transaction.amount, amt("100.0"));
+        assert_eq!(fetched_transaction.state, TxState::Processed);
+    }
+
+    #[test]
+    fn test_state_persists_across_open() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        {
+            let mut storage = SledStorage::open(dir).unwrap();
+            let mut account = Account::new(1);
+            account.total = amt("5.0");
+            storage.insert_account(&account).unwrap();
+        }
+
+        {
+            let storage = SledStorage::open(dir).unwrap();
+            let account = storage.get_account(1).unwrap().unwrap();
+            assert_eq!(account.total, amt("5.0"));
+        }
+
+        SledStorage::remove(dir).unwrap();
+        assert!(!dir.join(SledStorage::DB_NAME).exists());
+        let storage = SledStorage::open(dir).unwrap();
+        assert!(storage.get_account(1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reopen_right_after_close() {
+        // every close leaves sled something to flush in the background, which is what used to
+        // make the next open fail now and then
+        let dir = tempfile::tempdir().unwrap();
+        for round in 0..50 {
+            let mut storage = SledStorage::open(dir.path()).unwrap();
+            for client in round * 10..round * 10 + 10 {
+                storage.insert_account(&Account::new(client)).unwrap();
+            }
+        }
+        let storage = SledStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.accounts().count(), 500);
+    }
+
+    #[test]
+    fn test_lock_error_is_recognized() {
+        let dir = tempfile::tempdir().unwrap();
+        let _db = sled::open(dir.path()).unwrap();
+        let err = sled::open(dir.path()).unwrap_err();
+        assert!(is_lock_error(&err), "{}", err);
+        assert!(!is_lock_error(&sled::Error::Unsupported("x".to_string())));
+    }
+
+    #[test]
+    fn test_accounts_sorted_by_client() {
+        let mut storage = SledStorage::temporary().unwrap();
+        for id in [300, 7, 42, 1] {
+            storage.insert_account(&Account::new(id)).unwrap();
+        }
+
+        let ids: Vec<u16> = storage.accounts().map(|acc| acc.unwrap().id).collect();
+        assert_eq!(ids, vec![1, 7, 42, 300]);
+    }
+
+    #[test]
+    fn test_commit_writes_account_and_transaction() {
+        let mut storage = SledStorage::temporary().unwrap();
+        let mut account = Account::new(1);
+        account.total = amt("3.0");
+        let transaction = Transaction {
+            tx_type: TxType::Deposit,
+            client: 1,
+            tx: "tx1".to_string(),
+            amount: amt("3.0"),
+            state: TxState::Processed,
+        };
+
+        let event = DisputeEvent {
+            tx: "tx1".to_string(),
+            seq: 1,
+            event: TxType::Dispute,
+            client: 1,
+            amount: amt("3.0"),
+            state: TxState::Disputed,
+        };
+
+        let entry = JournalEntry::new(None, "tx1", TxType::Deposit, amt("3.0"), &account, &account);
+
+        storage
+            .commit(&Commit {
+                account: &account,
+                tx: Some(&transaction),
+                event: None,
+                journal: &entry,
+            })
+            .unwrap();
+        storage
+            .commit(&Commit {
+                account: &account,
+                tx: None,
+                event: Some(&event),
+                journal: &entry,
+            })
+            .unwrap();
+
+        assert_eq!(storage.get_account(1).unwrap(), Some(account));
+        assert_eq!(storage.transactions().count(), 1);
+        assert!(storage.get_transaction("tx1").unwrap().is_some());
+        assert_eq!(storage.events("tx1").unwrap(), vec![event]);
+        let seqs: Vec<u64> = storage.journal().map(|e| e.unwrap().seq).collect();
+        assert_eq!(seqs, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_journal_numbering_resumes_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = JournalEntry::new(
+            Some(1),
+            "1",
+            TxType::Deposit,
+            amt("1.0"),
+            &Account::new(1),
+            &Account::new(1),
+        );
+
+        {
+            let mut storage = SledStorage::open(dir.path()).unwrap();
+            assert_eq!(storage.append_journal(&entry).unwrap(), 1);
+            assert_eq!(storage.append_journal(&entry).unwrap(), 2);
+        }
+        let mut storage = SledStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.append_journal(&entry).unwrap(), 3);
+        let seqs: Vec<u64> = storage.journal().map(|e| e.unwrap().seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_events_keyed_by_tx_and_sequence() {
+        let mut storage = SledStorage::temporary().unwrap();
+        let event = |tx: &str, seq, event| DisputeEvent {
+            tx: tx.to_string(),
+            seq,
+            event,
+            client: 1,
+            amount: amt("1.0"),
+            state: TxState::Disputed,
+        };
+        // inserted out of order, and with ids that share a prefix
+        for recorded in [
+            event("5", 2, TxType::Resolve),
+            event("55", 1, TxType::Dispute),
+            event("5", 1, TxType::Dispute),
+            event("5-d", 1, TxType::Dispute),
+        ] {
+            storage.insert_event(&recorded).unwrap();
+        }
+
+        let seqs: Vec<u32> = storage.events("5").unwrap().iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2]);
+        assert_eq!(storage.events("55").unwrap().len(), 1);
+        assert_eq!(storage.events("5-d").unwrap().len(), 1);
+        assert!(storage.events("6").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_open_imports_legacy_layout() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        {
+            let ac_db = sled::open(dir.join(SledStorage::LEGACY_ACCOUNT_DB)).unwrap();
+            let mut account = Account::new(4);
+            account.total = amt("2.0");
+            ac_db
+                .insert(4u16.to_be_bytes(), to_vec(&account).unwrap())
+                .unwrap();
+            ac_db.flush().unwrap();
+        }
+
+        {
+            let storage = SledStorage::open(dir).unwrap();
+            assert!(storage.migrated_legacy());
+            assert_eq!(storage.get_account(4).unwrap().unwrap().total, amt("2.0"));
+            assert!(!dir.join(SledStorage::LEGACY_ACCOUNT_DB).exists());
+        }
+
+        let storage = SledStorage::open(dir).unwrap();
+        assert!(!storage.migrated_legacy());
+        assert!(storage.get_account(4).unwrap().is_some());
+    }
+
+    // records as the first version wrote them, with f32 amounts and an `under_dispute` flag
+    #[derive(Serialize)]
+    struct LegacyAccount {
+        id: u16,
+        available: f32,
+        held: f32,
+        total: f32,
+        locked: bool,
+    }
+
+    #[derive(Serialize)]
+    struct LegacyTransaction {
+        #[serde(rename = "type")]
+        tx_type: &'static str,
+        client: u16,
+        tx: &'static str,
+        amount: f32,
+        under_dispute: &'static str,
+    }
+
+    fn write_legacy(dir: &Path, name: &str, key: &[u8], record: &impl Serialize) {
+        let db = sled::open(dir.join(name)).unwrap();
+        db.insert(key, to_string(record).unwrap().as_bytes())
+            .unwrap();
+        db.flush().unwrap();
+    }
+
+    // `deposit,1,1,1.1`, `deposit,1,2,2.2` then `dispute,1,1`, as the first version ran them
+    fn write_legacy_ledger(dir: &Path) {
+        let total = 1.1f32 + 2.2f32;
+        let account = LegacyAccount {
+            id: 1,
+            available: total - 1.1f32,
+            held: 1.1,
+            total,
+            locked: false,
+        };
+        write_legacy(
+            dir,
+            SledStorage::LEGACY_ACCOUNT_DB,
+            &1u16.to_be_bytes(),
+            &account,
+        );
+        for (tx, amount, under_dispute) in [("1", 1.1, "true"), ("2", 2.2, "false")] {
+            let record = LegacyTransaction {
+                tx_type: "Deposit",
+                client: 1,
+                tx,
+                amount,
+                under_dispute,
+            };
+            write_legacy(
+                dir,
+                SledStorage::LEGACY_TRANSACTION_DB,
+                tx.as_bytes(),
+                &record,
+            );
+        }
+    }
+
+    #[test]
+    fn test_open_upgrades_legacy_records() {
+        let dir = tempfile::tempdir().unwrap();
+        write_legacy_ledger(dir.path());
+        let raw = sled::open(dir.path().join(SledStorage::LEGACY_ACCOUNT_DB)).unwrap();
+        let stored = raw.get(1u16.to_be_bytes()).unwrap().unwrap();
+        assert!(std::str::from_utf8(&stored)
+            .unwrap()
+            .contains(r#""total":3.3000002"#));
+        drop(raw);
+
+        let storage = SledStorage::open(dir.path()).unwrap();
+        let account = storage.get_account(1).unwrap().unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (amt("2.2"), amt("1.1"), amt("3.3"))
+        );
+        let states: Vec<(Amount, TxState)> = storage
+            .transactions()
+            .map(|tx| tx.map(|tx| (tx.amount, tx.state)).unwrap())
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                (amt("1.1"), TxState::Disputed),
+                (amt("2.2"), TxState::Processed)
+            ]
+        );
+        assert_eq!(crate::recovery::check(&storage).unwrap(), vec![]);
+
+        let mut engine = Engine::new(storage);
+        engine
+            .apply(Transaction {
+                tx_type: TxType::Resolve,
+                client: 1,
+                tx: "1".to_string(),
+                amount: Amount::ZERO,
+                state: TxState::Processed,
+            })
+            .unwrap();
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.available, amt("3.3"));
+        assert_eq!(account.held, Amount::ZERO);
+    }
+
+    #[test]
+    fn test_unreadable_legacy_record_leaves_old_ledger_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        write_legacy_ledger(dir.path());
+        let record = json!({"type": "Deposit", "client": 1, "tx": "3", "amount": "ten"});
+        write_legacy(
+            dir.path(),
+            SledStorage::LEGACY_TRANSACTION_DB,
+            b"3",
+            &record,
+        );
+
+        let err = SledStorage::open(dir.path()).err().unwrap();
+        assert!(err.to_string().contains("left as it was"), "{}", err);
+        assert!(dir.path().join(SledStorage::LEGACY_ACCOUNT_DB).exists());
+        assert!(dir.path().join(SledStorage::LEGACY_TRANSACTION_DB).exists());
+        let storage = SledStorage::open(dir.path());
+        assert!(storage.is_err());
+    }
+
+    #[test]
+    fn test_legacy_amounts_are_rounded_from_their_text() {
+        for (number, expected) in [
+            ("10.0", "10.0"),
+            ("3.3000002", "3.3"),
+            ("2.2000003", "2.2"),
+            ("0.00005", "0.0001"),
+            ("-0.00005", "-0.0001"),
+            ("1.23444999", "1.2344"),
+            ("7", "7"),
+            ("1e-5", "0"),
+            ("1.5E3", "1500"),
+            ("1e+2", "100"),
+            ("123456789012.5", "123456789012.5"),
+        ] {
+            assert_eq!(legacy_amount(number), Some(amt(expected)), "{}", number);
+        }
+        for number in ["1e16", "0x10", "", "1.2.3", "e5", "1e99999999999"] {
+            assert_eq!(legacy_amount(number), None, "{}", number);
+        }
+        assert_eq!(legacy_amount("0e99999999"), Some(Amount::ZERO));
+    }
+
+    #[test]
+    fn test_open_refuses_a_ledger_in_use() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let storage = SledStorage::open(dir).unwrap();
+        assert!(matches!(SledStorage::open(dir), Err(Error::Locked(_))));
+        assert!(matches!(SledStorage::remove(dir), Err(Error::Locked(_))));
+        drop(storage);
+
+        SledStorage::open(dir).unwrap();
+        SledStorage::remove(dir).unwrap();
+        assert!(dir.join(SledStorage::LOCK_NAME).exists());
+        SledStorage::open(dir).unwrap();
+    }
+
+    #[test]
+    fn test_temporary_in_is_unique_and_cleaned_up() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut first = SledStorage::temporary_in(dir.path()).unwrap();
+        let second = SledStorage::temporary_in(dir.path()).unwrap();
+        first.insert_account(&Account::new(1)).unwrap();
+        assert!(second.get_account(1).unwrap().is_none());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        drop(first);
+        drop(second);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
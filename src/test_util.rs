@@ -0,0 +1,96 @@
+// Helpers shared by the unit tests of every module
+
+use crate::account::Account;
+use crate::amount::Amount;
+use crate::error::Result;
+use crate::journal::JournalEntry;
+use crate::storage::{AccountIter, Commit, JournalIter, MemoryStorage, Storage, TransactionIter};
+use crate::transaction::{DisputeEvent, Transaction, TxState, TxType};
+
+pub(crate) fn amt(s: &str) -> Amount {
+    s.parse().unwrap()
+}
+
+pub(crate) fn deposit(client: u16, tx: &str, amount: &str) -> Transaction {
+    Transaction {
+        tx_type: TxType::Deposit,
+        client,
+        tx: tx.to_string(),
+        amount: amt(amount),
+        state: TxState::Processed,
+    }
+}
+
+// A `MemoryStorage` that counts its flushes and runs a hook before every commit and flush, so
+// tests can make either of them fail
+pub(crate) struct TestStorage {
+    pub(crate) inner: MemoryStorage,
+    pub(crate) flushes: usize,
+    // an error fails the commit before anything is written
+    pub(crate) commit_hook: fn(&Commit) -> Result<()>,
+    pub(crate) flush_hook: fn() -> Result<()>,
+}
+
+impl Default for TestStorage {
+    fn default() -> TestStorage {
+        TestStorage {
+            inner: MemoryStorage::new(),
+            flushes: 0,
+            commit_hook: |_| Ok(()),
+            flush_hook: || Ok(()),
+        }
+    }
+}
+
+impl Storage for TestStorage {
+    fn get_account(&self, client_id: u16) -> Result<Option<Account>> {
+        self.inner.get_account(client_id)
+    }
+
+    fn insert_account(&mut self, account: &Account) -> Result<()> {
+        self.inner.insert_account(account)
+    }
+
+    fn get_transaction(&self, tx_id: &str) -> Result<Option<Transaction>> {
+        self.inner.get_transaction(tx_id)
+    }
+
+    fn insert_transaction(&mut self, tx: &Transaction) -> Result<()> {
+        self.inner.insert_transaction(tx)
+    }
+
+    fn insert_event(&mut self, event: &DisputeEvent) -> Result<()> {
+        self.inner.insert_event(event)
+    }
+
+    fn events(&self, tx_id: &str) -> Result<Vec<DisputeEvent>> {
+        self.inner.events(tx_id)
+    }
+
+    fn append_journal(&mut self, entry: &JournalEntry) -> Result<u64> {
+        self.inner.append_journal(entry)
+    }
+
+    fn journal(&self) -> JournalIter<'_> {
+        self.inner.journal()
+    }
+
+    fn commit(&mut self, commit: &Commit) -> Result<()> {
+        (self.commit_hook)(commit)?;
+        self.inner.commit(commit)
+    }
+
+    fn accounts(&self) -> AccountIter<'_> {
+        self.inner.accounts()
+    }
+
+    fn transactions(&self) -> TransactionIter<'_> {
+        self.inner.transactions()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.flushes += 1;
+        (self.flush_hook)()?;
+        self.inner.flush()
+    }
+}
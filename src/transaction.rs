@@ -1,24 +1,41 @@
+use std::fmt;
+
 use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::account::Account;
+use crate::amount::Amount;
+use crate::error::{Result, RuleViolation};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub tx_type: TxType,
     pub client: u16,
     pub tx: String,
-    #[serde(deserialize_with = "deserialize_amount")]
-    pub amount: f32,
-    #[serde(
-        default = "default_bool",
-        deserialize_with = "deserialize_dispute",
-        serialize_with = "bool_to_string"
-    )]
-    pub under_dispute: bool,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub amount: Amount,
+    #[serde(default)]
+    pub state: TxState,
+}
+
+// One dispute, resolve or chargeback applied to a deposit or withdrawal. Events are kept apart
+// from the transactions they refer to, under the original tx id and a sequence number that
+// starts at 1, so they can never collide with a real transaction id.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DisputeEvent {
+    pub tx: String,
+    pub seq: u32,
+    #[serde(rename = "type")]
+    pub event: TxType,
+    pub client: u16,
+    // the amount of the referenced transaction, which is what the event moved
+    pub amount: Amount,
+    // the state the transaction was left in
+    pub state: TxState,
 }
 
-#[derive(Clone, Debug, Serialize, PartialEq)]
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 pub enum TxType {
     Deposit,
     Withdrawal,
@@ -27,50 +44,126 @@ pub enum TxType {
     Chargeback,
 }
 
-impl Transaction {
-    pub const DB_NAME: &'static str = "transation_db";
+// Where a deposit or withdrawal stands in the dispute lifecycle:
+//
+//   Processed --dispute--> Disputed --resolve----> Resolved
+//                                   --chargeback-> ChargedBack
+//
+// Resolved and ChargedBack are final, a transaction can only be disputed once.
+// Older ledgers stored the states capitalized, hence the aliases.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TxState {
+    #[default]
+    #[serde(alias = "Processed")]
+    Processed,
+    #[serde(alias = "Disputed")]
+    Disputed,
+    #[serde(alias = "Resolved")]
+    Resolved,
+    #[serde(alias = "ChargedBack")]
+    ChargedBack,
+}
 
-    pub fn deposit(&self, acc: &mut Account) {
-        acc.total += self.amount;
-        acc.available += self.amount;
+impl TxState {
+    // the state reached by applying `event`, or the rule it would break
+    pub fn transition(self, event: TxType) -> Result<TxState> {
+        match (self, event) {
+            (TxState::Processed, TxType::Dispute) => Ok(TxState::Disputed),
+            (TxState::Disputed, TxType::Resolve) => Ok(TxState::Resolved),
+            (TxState::Disputed, TxType::Chargeback) => Ok(TxState::ChargedBack),
+            (_, TxType::Resolve | TxType::Chargeback) => Err(RuleViolation::NotDisputed.into()),
+            (from, _) => Err(RuleViolation::AlreadyDisputed(from).into()),
+        }
     }
+}
 
-    pub fn withdrawal(&self, acc: &mut Account) {
+impl fmt::Display for TxState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            TxState::Processed => "processed",
+            TxState::Disputed => "disputed",
+            TxState::Resolved => "resolved",
+            TxState::ChargedBack => "charged back",
+        };
+        f.write_str(state)
+    }
+}
+
+impl Transaction {
+    pub fn deposit(&self, acc: &mut Account) -> Result<()> {
+        acc.total = acc.total.checked_add(self.amount)?;
+        acc.available = acc.available.checked_add(self.amount)?;
+        Ok(())
+    }
+
+    pub fn withdrawal(&self, acc: &mut Account) -> Result<()> {
         if self.amount > acc.available {
-            return;
+            return Err(RuleViolation::InsufficientFunds.into());
         }
-        acc.available -= self.amount;
-        acc.total -= self.amount;
+        acc.available = acc.available.checked_sub(self.amount)?;
+        acc.total = acc.total.checked_sub(self.amount)?;
+        Ok(())
     }
 
-    pub fn dispute(&mut self, acc: &mut Account) {
-        acc.available -= self.amount;
-        acc.held += self.amount;
-        self.under_dispute = true;
+    // Disputing a deposit holds the deposited funds. Disputing a withdrawal is a claim that the
+    // money should not have left, so it is credited back provisionally but kept in held.
+    pub fn dispute(&mut self, acc: &mut Account) -> Result<()> {
+        let next = self.state.transition(TxType::Dispute)?;
+        match self.tx_type {
+            TxType::Withdrawal => {
+                acc.held = acc.held.checked_add(self.amount)?;
+                acc.total = acc.total.checked_add(self.amount)?;
+            }
+            _ => {
+                acc.available = acc.available.checked_sub(self.amount)?;
+                acc.held = acc.held.checked_add(self.amount)?;
+            }
+        }
+        self.state = next;
+        Ok(())
     }
 
-    pub fn resolve(&mut self, acc: &mut Account) {
-        if !self.under_dispute {
-            return;
+    // The dispute is dropped: held funds go back to available, and a provisional credit is
+    // taken back since the withdrawal stands.
+    pub fn resolve(&mut self, acc: &mut Account) -> Result<()> {
+        let next = self.state.transition(TxType::Resolve)?;
+        match self.tx_type {
+            TxType::Withdrawal => {
+                acc.held = acc.held.checked_sub(self.amount)?;
+                acc.total = acc.total.checked_sub(self.amount)?;
+            }
+            _ => {
+                acc.available = acc.available.checked_add(self.amount)?;
+                acc.held = acc.held.checked_sub(self.amount)?;
+            }
         }
-        acc.available += self.amount;
-        acc.held -= self.amount;
-        self.under_dispute = false;
+        self.state = next;
+        Ok(())
     }
 
-    pub fn chargeback(&mut self, acc: &mut Account) {
-        if !self.under_dispute {
-            return;
+    // The transaction is reversed: a deposit is taken out of the account, a withdrawal is
+    // refunded by releasing its provisional credit. Either way the account gets locked.
+    pub fn chargeback(&mut self, acc: &mut Account) -> Result<()> {
+        let next = self.state.transition(TxType::Chargeback)?;
+        match self.tx_type {
+            TxType::Withdrawal => {
+                acc.held = acc.held.checked_sub(self.amount)?;
+                acc.available = acc.available.checked_add(self.amount)?;
+            }
+            _ => {
+                acc.total = acc.total.checked_sub(self.amount)?;
+                acc.held = acc.held.checked_sub(self.amount)?;
+            }
         }
-        acc.total -= self.amount;
-        acc.held -= self.amount;
         acc.locked = true;
-        self.under_dispute = false;
+        self.state = next;
+        Ok(())
     }
 }
 
 impl<'de> Deserialize<'de> for TxType {
-    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    fn deserialize<D>(de: D) -> std::result::Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
@@ -81,232 +174,282 @@ impl<'de> Deserialize<'de> for TxType {
             "Dispute" | "dispute" => TxType::Dispute,
             "Resolve" | "resolve" => TxType::Resolve,
             "Chargeback" | "chargeback" => TxType::Chargeback,
-            _ => panic!("Type variant unknown: {:?}", variant.as_str()),
+            _ => {
+                return Err(serde::de::Error::custom(format!(
+                    "Type variant unknown: {:?}",
+                    variant
+                )))
+            }
         })
     }
 }
 
 // when amount is missing (disputes, resolves, chargebacks), default value is set to 0.0
-fn deserialize_amount<'de, D>(deserializer: D) -> Result<f32, D::Error>
+fn deserialize_amount<'de, D>(deserializer: D) -> std::result::Result<Amount, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
-    let s: Option<f32> = Option::deserialize(deserializer)?;
+    let s: Option<Amount> = Option::deserialize(deserializer)?;
     match s {
         // set amount value to abs so it won't allow negative operations
-        Some(s) => Ok(s.abs()),
-        None => Ok(0f32),
-    }
-}
-
-fn deserialize_dispute<'de, D>(deserializer: D) -> Result<bool, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    let s: Option<String> = Option::deserialize(deserializer)?;
-    match s {
-        Some(b) => Ok(b.parse().map_err(serde::de::Error::custom))?,
-        None => Ok(false),
+        Some(s) => s.checked_abs().map_err(serde::de::Error::custom),
+        None => Ok(Amount::ZERO),
     }
 }
 
-fn bool_to_string<S>(value: &bool, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    let s = if *value { "true" } else { "false" };
-    serializer.serialize_str(s)
-}
-
-fn default_bool() -> bool {
-    false
-}
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::account::Account; // Assuming Account is defined in crate::account
+    use crate::error::Error;
+    use crate::test_util::amt;
 
     #[test]
     fn test_deposit() {
         let mut account = Account {
             id: 1,
-            total: 0.0,
-            available: 0.0,
-            held: 0.0,
+            total: amt("0.0"),
+            available: amt("0.0"),
+            held: amt("0.0"),
             locked: false,
         };
         let transaction = Transaction {
             tx_type: TxType::Deposit,
             client: 1,
             tx: "1".to_string(),
-            amount: 100.0,
-            under_dispute: false,
+            amount: amt("100.0"),
+            state: TxState::Processed,
         };
 
-        transaction.deposit(&mut account);
-        assert_eq!(account.total, 100.0);
-        assert_eq!(account.available, 100.0);
+        transaction.deposit(&mut account).unwrap();
+        assert_eq!(account.total, amt("100.0"));
+        assert_eq!(account.available, amt("100.0"));
     }
 
     #[test]
     fn test_withdrawal() {
         let mut account = Account {
             id: 1,
-            total: 100.0,
-            available: 100.0,
-            held: 0.0,
+            total: amt("100.0"),
+            available: amt("100.0"),
+            held: amt("0.0"),
             locked: false,
         };
         let transaction = Transaction {
             tx_type: TxType::Withdrawal,
             client: 1,
             tx: "2".to_string(),
-            amount: 50.0,
-            under_dispute: false,
+            amount: amt("50.0"),
+            state: TxState::Processed,
         };
 
-        transaction.withdrawal(&mut account);
-        assert_eq!(account.total, 50.0);
-        assert_eq!(account.available, 50.0);
+        transaction.withdrawal(&mut account).unwrap();
+        assert_eq!(account.total, amt("50.0"));
+        assert_eq!(account.available, amt("50.0"));
     }
 
     #[test]
     fn test_withdrawal_insufficient_funds() {
         let mut account = Account {
             id: 1,
-            total: 50.0,
-            available: 50.0,
-            held: 0.0,
+            total: amt("50.0"),
+            available: amt("50.0"),
+            held: amt("0.0"),
             locked: false,
         };
         let transaction = Transaction {
             tx_type: TxType::Withdrawal,
             client: 1,
             tx: "3".to_string(),
-            amount: 100.0,
-            under_dispute: false,
+            amount: amt("100.0"),
+            state: TxState::Processed,
         };
 
-        transaction.withdrawal(&mut account);
-        assert_eq!(account.total, 50.0); // No change
-        assert_eq!(account.available, 50.0); // No change
+        assert!(matches!(
+            transaction.withdrawal(&mut account),
+            Err(Error::Rule(RuleViolation::InsufficientFunds))
+        ));
+        assert_eq!(account.total, amt("50.0")); // No change
+        assert_eq!(account.available, amt("50.0")); // No change
     }
 
     #[test]
     fn test_dispute() {
         let mut account = Account {
             id: 1,
-            total: 100.0,
-            available: 100.0,
-            held: 0.0,
+            total: amt("100.0"),
+            available: amt("100.0"),
+            held: amt("0.0"),
             locked: false,
         };
         let mut transaction = Transaction {
             tx_type: TxType::Dispute,
             client: 1,
             tx: "4".to_string(),
-            amount: 50.0,
-            under_dispute: false,
+            amount: amt("50.0"),
+            state: TxState::Processed,
         };
 
-        transaction.dispute(&mut account);
-        assert_eq!(account.available, 50.0);
-        assert_eq!(account.held, 50.0);
-        assert!(transaction.under_dispute);
+        transaction.dispute(&mut account).unwrap();
+        assert_eq!(account.available, amt("50.0"));
+        assert_eq!(account.held, amt("50.0"));
+        assert_eq!(transaction.state, TxState::Disputed);
     }
 
     #[test]
     fn test_resolve() {
         let mut account = Account {
             id: 1,
-            total: 100.0,
-            available: 50.0,
-            held: 50.0,
+            total: amt("100.0"),
+            available: amt("50.0"),
+            held: amt("50.0"),
             locked: false,
         };
         let mut transaction = Transaction {
             tx_type: TxType::Resolve,
             client: 1,
             tx: "5".to_string(),
-            amount: 50.0,
-            under_dispute: true,
+            amount: amt("50.0"),
+            state: TxState::Disputed,
         };
 
-        transaction.resolve(&mut account);
-        assert_eq!(account.available, 100.0);
-        assert_eq!(account.held, 0.0);
-        assert!(!transaction.under_dispute);
+        transaction.resolve(&mut account).unwrap();
+        assert_eq!(account.available, amt("100.0"));
+        assert_eq!(account.held, amt("0.0"));
+        assert_eq!(transaction.state, TxState::Resolved);
     }
 
     #[test]
     fn test_resolve_not_under_dispute() {
         let mut account = Account {
             id: 1,
-            total: 100.0,
-            available: 100.0,
-            held: 0.0,
+            total: amt("100.0"),
+            available: amt("100.0"),
+            held: amt("0.0"),
             locked: false,
         };
         let mut transaction = Transaction {
             tx_type: TxType::Resolve,
             client: 1,
             tx: "6".to_string(),
-            amount: 50.0,
-            under_dispute: false,
+            amount: amt("50.0"),
+            state: TxState::Processed,
         };
 
-        transaction.resolve(&mut account);
-        assert_eq!(account.available, 100.0); // No change
-        assert_eq!(account.held, 0.0); // No change
-        assert!(!transaction.under_dispute);
+        assert!(matches!(
+            transaction.resolve(&mut account),
+            Err(Error::Rule(RuleViolation::NotDisputed))
+        ));
+        assert_eq!(account.available, amt("100.0")); // No change
+        assert_eq!(account.held, amt("0.0")); // No change
+        assert_eq!(transaction.state, TxState::Processed);
     }
 
     #[test]
     fn test_chargeback() {
         let mut account = Account {
             id: 1,
-            total: 100.0,
-            available: 50.0,
-            held: 50.0,
+            total: amt("100.0"),
+            available: amt("50.0"),
+            held: amt("50.0"),
             locked: false,
         };
         let mut transaction = Transaction {
             tx_type: TxType::Chargeback,
             client: 1,
             tx: "7".to_string(),
-            amount: 50.0,
-            under_dispute: true,
+            amount: amt("50.0"),
+            state: TxState::Disputed,
         };
 
-        transaction.chargeback(&mut account);
-        assert_eq!(account.total, 50.0);
-        assert_eq!(account.held, 0.0);
+        transaction.chargeback(&mut account).unwrap();
+        assert_eq!(account.total, amt("50.0"));
+        assert_eq!(account.held, amt("0.0"));
         assert!(account.locked);
-        assert!(!transaction.under_dispute);
+        assert_eq!(transaction.state, TxState::ChargedBack);
     }
 
     #[test]
     fn test_chargeback_not_under_dispute() {
         let mut account = Account {
             id: 1,
-            total: 100.0,
-            available: 100.0,
-            held: 0.0,
+            total: amt("100.0"),
+            available: amt("100.0"),
+            held: amt("0.0"),
             locked: false,
         };
         let mut transaction = Transaction {
             tx_type: TxType::Chargeback,
             client: 1,
             tx: "8".to_string(),
-            amount: 50.0,
-            under_dispute: false,
+            amount: amt("50.0"),
+            state: TxState::Processed,
         };
 
-        transaction.chargeback(&mut account);
-        assert_eq!(account.total, 100.0); // No change
-        assert_eq!(account.held, 0.0); // No change
+        assert!(matches!(
+            transaction.chargeback(&mut account),
+            Err(Error::Rule(RuleViolation::NotDisputed))
+        ));
+        assert_eq!(account.total, amt("100.0")); // No change
+        assert_eq!(account.held, amt("0.0")); // No change
+        assert!(!account.locked);
+        assert_eq!(transaction.state, TxState::Processed);
+    }
+
+    fn withdrawn_account() -> (Account, Transaction) {
+        let account = Account {
+            id: 1,
+            total: amt("60.0"),
+            available: amt("60.0"),
+            held: amt("0.0"),
+            locked: false,
+        };
+        let transaction = Transaction {
+            tx_type: TxType::Withdrawal,
+            client: 1,
+            tx: "13".to_string(),
+            amount: amt("40.0"),
+            state: TxState::Processed,
+        };
+        (account, transaction)
+    }
+
+    #[test]
+    fn test_dispute_withdrawal_provisional_credit() {
+        let (mut account, mut transaction) = withdrawn_account();
+
+        transaction.dispute(&mut account).unwrap();
+        assert_eq!(account.available, amt("60.0"));
+        assert_eq!(account.held, amt("40.0"));
+        assert_eq!(account.total, amt("100.0"));
+        assert_eq!(transaction.state, TxState::Disputed);
+    }
+
+    #[test]
+    fn test_resolve_disputed_withdrawal() {
+        let (mut account, mut transaction) = withdrawn_account();
+
+        transaction.dispute(&mut account).unwrap();
+        transaction.resolve(&mut account).unwrap();
+        // the withdrawal stands, the provisional credit is gone
+        assert_eq!(account.available, amt("60.0"));
+        assert_eq!(account.held, amt("0.0"));
+        assert_eq!(account.total, amt("60.0"));
         assert!(!account.locked);
-        assert!(!transaction.under_dispute);
+    }
+
+    #[test]
+    fn test_chargeback_disputed_withdrawal() {
+        let (mut account, mut transaction) = withdrawn_account();
+
+        transaction.dispute(&mut account).unwrap();
+        transaction.chargeback(&mut account).unwrap();
+        // the withdrawal is reversed, the client gets the money back
+        assert_eq!(account.available, amt("100.0"));
+        assert_eq!(account.held, amt("0.0"));
+        assert_eq!(account.total, amt("100.0"));
+        assert!(account.locked);
+        assert_eq!(transaction.state, TxState::ChargedBack);
     }
 
     #[test]
@@ -317,43 +460,115 @@ mod tests {
             "type": "deposit",
             "client": 1,
             "tx": "9",
-            "amount": -100.0
+            "amount": "-100.0"
         });
 
         let transaction: Transaction = serde_json::from_value(json_data).unwrap();
-        assert_eq!(transaction.amount, 100.0); // Absolute value
+        assert_eq!(transaction.amount, amt("100.0")); // Absolute value
+    }
+
+    #[test]
+    fn test_deserialize_amount_too_many_decimals() {
+        let csv_data = "type,client,tx,amount\ndeposit,1,1,1.23456\n";
+        let mut reader = csv::Reader::from_reader(csv_data.as_bytes());
+        let result = reader.deserialize::<Transaction>().next().unwrap();
+        assert!(result.is_err());
     }
 
     #[test]
-    fn test_deserialize_dispute() {
+    fn test_deserialize_state() {
         use serde_json::json;
 
         let json_data = json!({
-            "type": "dispute",
+            "type": "deposit",
             "client": 1,
             "tx": "10",
-            "amount": 100.0,
-            "under_dispute": "true"
+            "amount": "100.0",
+            "state": "Disputed"
         });
 
         let transaction: Transaction = serde_json::from_value(json_data).unwrap();
-        assert_eq!(transaction.under_dispute, true);
+        assert_eq!(transaction.state, TxState::Disputed);
+
+        // rows from the input file don't carry a state
+        let csv_data = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let mut reader = csv::Reader::from_reader(csv_data.as_bytes());
+        let transaction: Transaction = reader.deserialize().next().unwrap().unwrap();
+        assert_eq!(transaction.state, TxState::Processed);
     }
 
     #[test]
-    fn test_bool_to_string() {
+    fn test_state_serialized() {
         use serde_json::to_string;
 
         let transaction = Transaction {
             tx_type: TxType::Deposit,
             client: 1,
             tx: "11".to_string(),
-            amount: 100.0,
-            under_dispute: true,
+            amount: amt("100.0"),
+            state: TxState::ChargedBack,
         };
 
         let json = to_string(&transaction).unwrap();
-        assert!(json.contains("\"under_dispute\":\"true\""));
+        assert!(json.contains("\"type\":\"deposit\""));
+        assert!(json.contains("\"state\":\"charged_back\""));
+
+        // records written by older versions still read back
+        let old = json
+            .replace("deposit", "Deposit")
+            .replace("charged_back", "ChargedBack");
+        let transaction: Transaction = serde_json::from_str(&old).unwrap();
+        assert_eq!(transaction.state, TxState::ChargedBack);
+    }
+
+    #[test]
+    fn test_state_transitions() {
+        use TxState::*;
+        use TxType::{Chargeback, Dispute, Resolve};
+
+        assert_eq!(Processed.transition(Dispute).unwrap(), Disputed);
+        assert_eq!(Disputed.transition(Resolve).unwrap(), Resolved);
+        assert_eq!(Disputed.transition(Chargeback).unwrap(), ChargedBack);
+
+        for state in [Processed, Resolved, ChargedBack] {
+            for event in [Resolve, Chargeback] {
+                assert!(matches!(
+                    state.transition(event),
+                    Err(Error::Rule(RuleViolation::NotDisputed))
+                ));
+            }
+        }
+        for state in [Disputed, Resolved, ChargedBack] {
+            assert!(matches!(
+                state.transition(Dispute),
+                Err(Error::Rule(RuleViolation::AlreadyDisputed(from))) if from == state
+            ));
+        }
+    }
+
+    #[test]
+    fn test_dispute_twice_holds_once() {
+        let mut account = Account::new(1);
+        account.available = amt("100.0");
+        account.total = amt("100.0");
+        let mut transaction = Transaction {
+            tx_type: TxType::Deposit,
+            client: 1,
+            tx: "12".to_string(),
+            amount: amt("100.0"),
+            state: TxState::Processed,
+        };
+
+        transaction.dispute(&mut account).unwrap();
+        assert!(transaction.dispute(&mut account).is_err());
+        assert_eq!(account.held, amt("100.0"));
+        assert_eq!(account.available, amt("0.0"));
+
+        // once resolved, the dispute is closed for good
+        transaction.resolve(&mut account).unwrap();
+        assert!(transaction.dispute(&mut account).is_err());
+        assert_eq!(account.held, amt("0.0"));
+        assert_eq!(account.available, amt("100.0"));
     }
 
     #[test]
@@ -380,5 +595,12 @@ mod tests {
         let tx_type: TxType = from_str(json_data).unwrap();
         assert_eq!(tx_type, TxType::Chargeback);
     }
-}
 
+    #[test]
+    fn test_tx_type_deserialize_unknown() {
+        use serde_json::from_str;
+
+        let result = from_str::<TxType>("\"transfer\"");
+        assert!(result.is_err());
+    }
+}